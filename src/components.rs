@@ -1,7 +1,13 @@
 use edict::prelude::Component;
-use glam::Vec3A;
+use glam::{Quat, Vec2, Vec3A};
 
-use crate::voxel_model::VoxelModel;
+use crate::{
+    utils::rendering::{
+        camera_basis, gen_frustum_planes_from_basis, gen_ortho_planes_from_basis, isometric_pitch,
+        FrustumPlane, Viewport, FAR, NEAR
+    },
+    voxel_model::VoxelModel
+};
 
 #[derive(Clone, Copy, Component)]
 pub struct Position{ pub value: Vec3A }
@@ -9,8 +15,127 @@ pub struct Position{ pub value: Vec3A }
 #[derive(Clone, Component)]
 pub struct Voxel{ pub data: VoxelModel }
 
+/// Turns a `Voxel` model about its center
+#[derive(Clone, Copy, Component)]
+pub struct Rotation{ pub value: Quat }
+
+/// Uniform scale of a `Voxel` model, growing it away from its `Position`
+#[derive(Clone, Copy, Component)]
+pub struct Scale{ pub value: f32 }
+
 #[derive(Clone, Copy, Component)]
 pub struct ViewAngle{ pub value: f32 }
 
+/// Pitch and roll applied on top of the yaw stored in `ViewAngle`
+#[derive(Clone, Copy, Default, Component)]
+pub struct ViewTilt{ pub pitch: f32, pub roll: f32 }
+
 #[derive(Clone, Copy, Component)]
 pub struct PlayerTag;
+
+/// Hit points shown as a bar by the HUD when the entity is the player
+#[derive(Clone, Copy, Component)]
+pub struct Health{ pub current: f32, pub max: f32 }
+
+/// Rounds left, shown as a bar by the HUD when the entity is the player
+#[derive(Clone, Copy, Component)]
+pub struct Ammo{ pub current: u32, pub max: u32 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Projection {
+    Perspective,
+    /// Parallel rays along the orientation of the entity
+    Orthographic,
+    /// Parallel rays looking down along a diagonal of the voxel grid, turned by the `ViewAngle`
+    Isometric,
+    /// Parallel rays looking straight down, with the `ViewAngle` turning the map
+    TopDown
+}
+
+/// Pixels a camera traces, the others are filled from traced neighbours which agree on what they
+/// show and traced as well where they do not. The traced pixels shift from frame to frame, and
+/// while the camera stays put the others keep what was traced for them in the frames before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsampling {
+    /// Every pixel is traced
+    Off,
+    /// Every other pixel, filled from the four pixels around it
+    Checkerboard,
+    /// One pixel in every 2x2 block, filled from the traced pixels on either side or diagonally
+    Grid2x2
+}
+
+/// View rendered into `viewport`, placed by the `Position`, `ViewAngle` and optional `ViewTilt`
+/// of its entity. Inactive cameras are skipped, so cutscene or spectator cameras can stay spawned.
+///
+/// `zoom` narrows the field of view of perspective cameras, while parallel projections show
+/// `zoom` pixels per voxel. `subsampling` trades some detail along edges for tracing fewer rays.
+#[derive(Clone, Copy, Component)]
+pub struct Camera{
+    pub fov_slope: f32,
+    pub near: f32,
+    pub far: f32,
+    pub viewport: Viewport,
+    pub active: bool,
+    pub projection: Projection,
+    pub zoom: f32,
+    pub subsampling: Subsampling
+}
+
+impl Camera {
+    pub fn new(viewport: Viewport) -> Self {
+        Self {
+            fov_slope: 1.125,
+            near: NEAR,
+            far: FAR,
+            viewport,
+            active: true,
+            projection: Projection::Perspective,
+            zoom: 1.0,
+            subsampling: Subsampling::Off
+        }
+    }
+
+    pub fn frustum_planes(&self, pos: Vec3A, yaw: f32, tilt: ViewTilt) -> [FrustumPlane; 2] {
+        let aspect_ratio = self.viewport.aspect_ratio();
+        let ortho_half_width = self.viewport.width as f32 * 0.5 / self.zoom;
+        let ortho = |basis| gen_ortho_planes_from_basis(pos, basis, ortho_half_width, aspect_ratio, self.near, self.far);
+
+        match self.projection {
+            Projection::Perspective => gen_frustum_planes_from_basis(
+                pos,
+                camera_basis(yaw, tilt.pitch, tilt.roll),
+                self.fov_slope / self.zoom,
+                aspect_ratio,
+                self.near,
+                self.far
+            ),
+            Projection::Orthographic => ortho(camera_basis(yaw, tilt.pitch, tilt.roll)),
+            Projection::Isometric => ortho(camera_basis(yaw + 45.0f32.to_radians(), isometric_pitch(), 0.0)),
+            Projection::TopDown => ortho(camera_basis(yaw, -90.0f32.to_radians(), 0.0))
+        }
+    }
+}
+
+/// Rectangle of a sprite sheet, in pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SurfaceRegion{ pub x: usize, pub y: usize, pub width: usize, pub height: usize }
+
+/// Region of the sprite sheet drawn as a billboard facing the camera, `size` voxels large.
+/// `anchor` is the point of the region placed at the `Position` of its entity, as a fraction of
+/// the region from its top left corner, so `(0.5, 1.0)` stands a character on its feet.
+#[derive(Clone, Copy, Component)]
+pub struct Sprite{
+    pub region: SurfaceRegion,
+    pub anchor: Vec2,
+    pub size: Vec2
+}
+
+/// Light shining from the `Position` of its entity, fading out at `radius`.
+/// Surfaces it reaches get brighter and are tinted towards the palette entry `color_ramp`.
+#[derive(Clone, Copy, Component)]
+pub struct PointLight{
+    pub radius: f32,
+    pub intensity: f32,
+    pub color_ramp: u8
+}
@@ -1,45 +1,79 @@
-use components::{PlayerTag, Position, ViewAngle, Voxel};
 use edict::world::World;
-use glam::vec3a;
-use retro_blit::window::{RetroBlitContext, ContextHandler, WindowMode};
+use retro_blit::window::{RetroBlitContext, ContextHandler, KeyCode, KeyMods, WindowMode};
+use scenes::{demo_materials, spawn_demo_scene, PALETTE_CYCLES, TILES_2D_BYTES};
 use systems::logic::player_systems::RotateOnPlaceSystem;
+use resources::{DebugOverlay, HudMessages};
+use systems::rendering::hud::HudSystem;
+use systems::rendering::minimap::MinimapSystem;
+use systems::rendering::palette_animation::PaletteAnimationSystem;
+use systems::rendering::sprites::{SpriteRenderingSystem, SpriteSheet};
 use systems::rendering::voxels::VoxelRenderingSystem;
 use systems::rendering::ClearScreenSystem;
-use systems::{BaseSystem, SystemGroup};
-use utils::loaders::{create_voxel_model_from_2d_tile, load_xraw};
-use voxel_model::VoxelModel;
+use systems::{BaseSystem, SystemGroup, TimedSystem};
+use utils::loaders::load_palette_cycles;
+use utils::materials::MaterialTable;
+use utils::palette::cycled_indices;
+use utils::rendering::Viewport;
 
 pub mod systems;
 pub mod components;
+pub mod resources;
 pub mod utils;
 pub mod voxel_model;
+pub mod scenes;
 
-const TILES_2D_BYTES: &[u8] = include_bytes!("assets/tiles2d.im256");
-//const GRASS_XRAW: &[u8] = include_bytes!("assets/grass.vox.xraw");
-const GRASS_DIRT_CORNER_XRAW: &[u8] = include_bytes!("assets/grass_dirt_corner.vox.xraw");
+/// Color the screen is cleared with, which distant voxels fade into
+const BACKGROUND_COLOR_ID: u8 = 1;
+/// Shows or hides FPS, camera pose and system timings
+const DEBUG_OVERLAY_KEY: KeyCode = KeyCode::F3;
 
 struct App {
     world: World,
     root_system_group: SystemGroup,
     palette: Vec<[u8; 3]>,
+    materials: MaterialTable,
     tiles_2d: retro_blit::rendering::BlittableSurface
 }
 
 impl App {
     fn create_logic_systems() -> Box<dyn BaseSystem> {
-        Box::new(SystemGroup {
+        Box::new(TimedSystem::new("logic", Box::new(SystemGroup {
             systems: vec![
                 //Box::new(MoveForwardSystem),
                 Box::new(RotateOnPlaceSystem)
             ]
-        })
+        })))
     }
 
-    fn create_rendering_systems() -> Box<dyn BaseSystem> {
+    fn create_rendering_systems(
+        palette: &[[u8; 3]],
+        materials: &MaterialTable,
+        tiles_2d: &retro_blit::rendering::BlittableSurface
+    ) -> Box<dyn BaseSystem> {
+        let cycles = load_palette_cycles(PALETTE_CYCLES);
+        let cycled = cycled_indices(&cycles);
         Box::new(SystemGroup {
             systems: vec![
-                Box::new(ClearScreenSystem(1)),
-                Box::new(VoxelRenderingSystem::new())
+                Box::new(PaletteAnimationSystem::new(palette, cycles)),
+                Box::new(ClearScreenSystem(BACKGROUND_COLOR_ID)),
+                Box::new(TimedSystem::new(
+                    "voxels",
+                    Box::new(VoxelRenderingSystem::new(palette, &cycled, materials.clone(), BACKGROUND_COLOR_ID))
+                )),
+                Box::new(TimedSystem::new(
+                    "sprites",
+                    Box::new(SpriteRenderingSystem::new(SpriteSheet::from_surface(tiles_2d)))
+                )),
+                // in the bottom right corner of the player view
+                Box::new(TimedSystem::new(
+                    "minimap",
+                    Box::new(MinimapSystem::new(palette, &cycled, Viewport { x: 118, y: 54, width: 40, height: 40 }))
+                )),
+                // the 24 rows left below the 96 rows of the player view
+                Box::new(TimedSystem::new(
+                    "hud",
+                    Box::new(HudSystem::new(SpriteSheet::from_surface(tiles_2d), &cycled, Viewport { x: 0, y: 96, width: 160, height: 24 }))
+                ))
             ]
         })
     }
@@ -48,17 +82,18 @@ impl App {
         let (palette, tiles_2d) = retro_blit::format_loaders::im_256::Image
             ::load_from(TILES_2D_BYTES)
                 .unwrap();
+        let materials = demo_materials();
 
         let root_system_group = SystemGroup {
             systems: vec![
                 Self::create_logic_systems(),
-                Self::create_rendering_systems()
+                Self::create_rendering_systems(&palette, &materials, &tiles_2d)
             ]
         };
 
         let world = World::new();
 
-        Self { world, root_system_group, palette, tiles_2d }
+        Self { world, root_system_group, palette, materials, tiles_2d }
     }
 }
 
@@ -72,61 +107,25 @@ impl ContextHandler for App {
     }
 
     fn init(&mut self, ctx: &mut RetroBlitContext) {
-        let grass_tile = load_xraw(GRASS_DIRT_CORNER_XRAW);
-        let lava_tile = create_voxel_model_from_2d_tile(&self.tiles_2d, 64, 32);
-        let water_tile = create_voxel_model_from_2d_tile(&self.tiles_2d, 64, 64);
-        let sphere = VoxelModel::make_sphere32x32x32(0, 5);
-
         for (i, [red, green, blue]) in self.palette.iter().enumerate() {
             ctx.set_palette(i as u8, [*red, *green, *blue])
         }
 
-        let world = &mut self.world;
+        spawn_demo_scene(&mut self.world, &self.tiles_2d, &self.materials);
 
-        world.spawn(
-            (
-                PlayerTag,
-                Position { value: vec3a(0.0, -16.0, 80.0) },
-                ViewAngle { value: (0.0f32).to_radians() }
-            )
-        );
-
-        world.spawn(
-            (
-                Position { value: vec3a(-16.0, -48.0, 96.0) },
-                Voxel { data: lava_tile.clone() }
-            )
-        );
-        world.spawn(
-            (
-                Position { value: vec3a(-16.0, -48.0, 64.0) },
-                Voxel {
-                    data: water_tile.clone()
-                }
-            )
-        );
-        world.spawn(
-            (
-                Position { value: vec3a(-16.0, -48.0, 32.0) },
-                Voxel {
-                    data: lava_tile.clone()
-                }
-            )
-        );
-        world.spawn(
-            (
-                Position { value: vec3a(16.0, -48.0, 64.0) },
-                Voxel {
-                    data: grass_tile.clone()
-                }
-            )
-        );
-        world.spawn(
-            (
-                Position { value: vec3a(-32.0, 0.0, 164.0) },
-                Voxel { data: sphere }
-            )
-        );
+        let mut messages = HudMessages::default();
+        messages.push("FIND THE WAY OUT", 5.0);
+        self.world.insert_resource(messages);
+        // shown from the start in debug builds, `DEBUG_OVERLAY_KEY` toggles it either way
+        self.world.insert_resource(DebugOverlay { visible: cfg!(debug_assertions) });
+    }
+
+    fn on_key_down(&mut self, _ctx: &mut RetroBlitContext, key_code: KeyCode, _key_mods: KeyMods) {
+        if key_code == DEBUG_OVERLAY_KEY {
+            let mut debug = self.world.remove_resource::<DebugOverlay>().unwrap_or_default();
+            debug.toggle();
+            self.world.insert_resource(debug);
+        }
     }
 
     fn update(&mut self, ctx: &mut RetroBlitContext, dt: f32) {
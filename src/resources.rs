@@ -0,0 +1,137 @@
+use std::{collections::HashMap, time::Duration};
+
+use glam::Vec3A;
+
+/// Per-pixel depth written by the voxel renderer alongside the color buffer.
+///
+/// It matches the framebuffer pixel for pixel. Each value is the distance from the near plane
+/// along the ray of that pixel, `f32::INFINITY` where nothing was hit, so later passes can
+/// occlude sprites or apply fog without tracing the scene again.
+pub struct DepthBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>
+}
+
+impl DepthBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { width, height, data: vec![f32::INFINITY; width * height] }
+    }
+
+    pub fn clear(&mut self) {
+        self.data.fill(f32::INFINITY);
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+}
+
+/// Short texts shown by the HUD until their time runs out, newest last
+#[derive(Default)]
+pub struct HudMessages {
+    pub messages: Vec<HudMessage>
+}
+
+pub struct HudMessage {
+    pub text: String,
+    /// seconds left until the message disappears
+    pub time_left: f32
+}
+
+impl HudMessages {
+    pub fn push(&mut self, text: impl Into<String>, duration: f32) {
+        self.messages.push(HudMessage { text: text.into(), time_left: duration });
+    }
+
+    /// Counts the messages down by `dt` seconds, dropping the expired ones
+    pub fn tick(&mut self, dt: f32) {
+        for message in self.messages.iter_mut() {
+            message.time_left -= dt;
+        }
+        self.messages.retain(|message| message.time_left > 0.0);
+    }
+}
+
+/// Whether the HUD draws FPS, camera pose and system timings over the view
+#[derive(Clone, Copy, Default)]
+pub struct DebugOverlay {
+    pub visible: bool
+}
+
+impl DebugOverlay {
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+}
+
+/// How long the last run of each timed system took, in the order they first ran
+#[derive(Default)]
+pub struct FrameTimings {
+    pub entries: Vec<(&'static str, Duration)>
+}
+
+impl FrameTimings {
+    pub fn record(&mut self, name: &'static str, duration: Duration) {
+        match self.entries.iter_mut().find(|(entry, _)| *entry == name) {
+            Some((_, last)) => *last = duration,
+            None => self.entries.push((name, duration))
+        }
+    }
+}
+
+/// First voxel straight down from the middle of the bottom of an explored cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CellTop {
+    pub color_id: u8,
+    /// how far below the bottom of the cell the top of the voxel lies
+    pub depth: f32
+}
+
+/// Cells the player has seen, which the minimap reveals. Cells have a fixed size, so zooming
+/// the minimap in or out keeps what has been explored, and are stacked in layers, so every floor
+/// of a cave is mapped on its own.
+///
+/// What the minimap shows of a cell is looked up when it gets revealed and kept along with it,
+/// `None` standing for a cell with nothing below it. Cells keep showing what was there when the
+/// player first saw them, so doors or props turning later on only show up once they are cleared.
+#[derive(Default)]
+pub struct ExploredCells {
+    cells: HashMap<(i32, i32, i32), Option<CellTop>>
+}
+
+impl ExploredCells {
+    /// Width and depth of a cell, in voxels
+    pub const CELL_SIZE: f32 = 4.0;
+    /// Height of a layer of cells, in voxels
+    pub const LAYER_HEIGHT: f32 = 16.0;
+
+    pub fn cell_of(pos: Vec3A) -> (i32, i32, i32) {
+        (
+            (pos.x / Self::CELL_SIZE).floor() as i32,
+            (pos.y / Self::LAYER_HEIGHT).floor() as i32,
+            (pos.z / Self::CELL_SIZE).floor() as i32
+        )
+    }
+
+    /// Reveals the cell `pos` lies in, calling `top` with the middle of the bottom of the cell when it is new
+    pub fn reveal(&mut self, pos: Vec3A, top: impl FnOnce(Vec3A) -> Option<CellTop>) {
+        let (x, y, z) = Self::cell_of(pos);
+        let center = |c: i32| (c as f32 + 0.5) * Self::CELL_SIZE;
+        let bottom = Vec3A::new(center(x), y as f32 * Self::LAYER_HEIGHT, center(z));
+        self.cells.entry((x, y, z)).or_insert_with(|| top(bottom));
+    }
+
+    pub fn is_revealed(&self, pos: Vec3A) -> bool {
+        self.cells.contains_key(&Self::cell_of(pos))
+    }
+
+    /// What the cell `pos` lies in shows, `None` while it is unexplored
+    pub fn get(&self, pos: Vec3A) -> Option<Option<CellTop>> {
+        self.cells.get(&Self::cell_of(pos)).copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+    }
+}
@@ -0,0 +1,90 @@
+use edict::world::World;
+use glam::{vec2, vec3a, Vec3A};
+
+use crate::{
+    components::{Ammo, Camera, Health, PlayerTag, PointLight, Position, Sprite, SurfaceRegion, ViewAngle, Voxel},
+    utils::{
+        loaders::{create_voxel_model_from_2d_tile, load_xraw},
+        materials::{Glow, Material, MaterialTable},
+        rendering::Viewport
+    },
+    voxel_model::VoxelModel
+};
+
+pub const TILES_2D_BYTES: &[u8] = include_bytes!("assets/tiles2d.im256");
+pub const PALETTE_CYCLES: &str = include_str!("assets/palette_cycles.txt");
+//pub const GRASS_XRAW: &[u8] = include_bytes!("assets/grass.vox.xraw");
+pub const GRASS_DIRT_CORNER_XRAW: &[u8] = include_bytes!("assets/grass_dirt_corner.vox.xraw");
+
+/// Materials of the `tiles2d.im256` palette entries
+pub fn demo_materials() -> MaterialTable {
+    let lava = Material { emissive: true, ..Material::default() };
+    let water = Material { translucent: true, reflective: true, ..Material::default() };
+    MaterialTable::default()
+        .with(5, Material { glow: Some(Glow { radius: 64.0, intensity: 0.6 }), ..lava })
+        .with(8, lava)
+        .with(27, lava)
+        .with(17, water)
+        .with(18, water)
+        .with(19, water)
+        .with(21, water)
+}
+
+/// Spawns a voxel entity along with a light at the center of its bounds when its model glows
+fn spawn_voxel(world: &mut World, materials: &MaterialTable, pos: Vec3A, model: VoxelModel) {
+    if let Some(light) = materials.glow_light(&model) {
+        let [w, h, d] = model.size;
+        let center = pos + vec3a(w as f32, h as f32, d as f32) * 0.5;
+        world.spawn((Position { value: center }, light));
+    }
+    world.spawn((Position { value: pos }, Voxel { data: model }));
+}
+
+pub fn spawn_demo_scene(
+    world: &mut World,
+    tiles_2d: &retro_blit::rendering::BlittableSurface,
+    materials: &MaterialTable
+) {
+    let grass_tile = load_xraw(GRASS_DIRT_CORNER_XRAW);
+    let lava_tile = create_voxel_model_from_2d_tile(tiles_2d, 64, 32);
+    let water_tile = create_voxel_model_from_2d_tile(tiles_2d, 64, 64);
+    // stone rather than the lava orange, which glows
+    let sphere = VoxelModel::make_sphere32x32x32(0, 23);
+
+    world.spawn(
+        (
+            PlayerTag,
+            Position { value: vec3a(0.0, -16.0, 80.0) },
+            ViewAngle { value: (0.0f32).to_radians() },
+            Camera::new(Viewport { x: 0, y: 0, width: 160, height: 96 }),
+            Health { current: 100.0, max: 100.0 },
+            Ammo { current: 24, max: 50 }
+        )
+    );
+
+    spawn_voxel(world, materials, vec3a(-16.0, -48.0, 96.0), lava_tile.clone());
+    spawn_voxel(world, materials, vec3a(-16.0, -48.0, 64.0), water_tile);
+    spawn_voxel(world, materials, vec3a(-16.0, -48.0, 32.0), lava_tile);
+    spawn_voxel(world, materials, vec3a(16.0, -48.0, 64.0), grass_tile);
+    spawn_voxel(world, materials, vec3a(-32.0, 0.0, 164.0), sphere);
+
+    // a tree from the 2D tiles, standing on the floor behind the grass
+    world.spawn(
+        (
+            Position { value: vec3a(40.0, -40.0, 150.0) },
+            Sprite {
+                region: SurfaceRegion { x: 256, y: 0, width: 64, height: 80 },
+                anchor: vec2(0.5, 1.0),
+                size: vec2(48.0, 60.0)
+            }
+        )
+    );
+
+    // torch hanging between the lava pools
+    world.spawn(
+        (
+            Position { value: vec3a(0.0, -28.0, 96.0) },
+            PointLight { radius: 96.0, intensity: 0.8, color_ramp: 5 }
+        )
+    );
+}
@@ -1,6 +1,10 @@
+use std::time::Instant;
+
 use edict::world::World;
 use retro_blit::window::RetroBlitContext;
 
+use crate::resources::FrameTimings;
+
 pub mod rendering;
 pub mod logic;
 
@@ -19,3 +23,29 @@ impl BaseSystem for SystemGroup {
         }
     }
 }
+
+/// Runs a system and records how long it took into the `FrameTimings` resource, which the debug
+/// overlay of the HUD shows. It is the one place systems get timed, so wrapped systems need no
+/// `StopWatch` of their own.
+pub struct TimedSystem {
+    pub name: &'static str,
+    pub system: Box<dyn BaseSystem>
+}
+
+impl TimedSystem {
+    pub fn new(name: &'static str, system: Box<dyn BaseSystem>) -> Self {
+        Self { name, system }
+    }
+}
+
+impl BaseSystem for TimedSystem {
+    fn run(&mut self, ctx: &mut RetroBlitContext, world: &mut World, dt: f32) {
+        let start = Instant::now();
+        self.system.run(ctx, world, dt);
+        let elapsed = start.elapsed();
+
+        let mut timings = world.remove_resource::<FrameTimings>().unwrap_or_default();
+        timings.record(self.name, elapsed);
+        world.insert_resource(timings);
+    }
+}
@@ -0,0 +1,168 @@
+//! Golden image regression tests for the voxel renderer.
+//!
+//! Fixed scenes are rendered headlessly from fixed camera poses and compared against reference
+//! images in `src/assets/golden`. On a mismatch the actual frame and an image marking differing
+//! pixels are written to `target/golden`. Run with `UPDATE_GOLDEN=1` to rewrite the references
+//! after an intended change to the output.
+
+use std::path::{Path, PathBuf};
+
+use edict::world::World;
+use glam::{vec3a, Vec3A};
+
+use crate::{
+    components::{Camera, Position, Projection, Subsampling, ViewAngle, ViewTilt, Voxel},
+    scenes::{demo_materials, spawn_demo_scene, GRASS_DIRT_CORNER_XRAW, PALETTE_CYCLES, TILES_2D_BYTES},
+    systems::rendering::voxels::{TracingMode, VoxelRenderer},
+    utils::{
+        image_writers::save_image,
+        loaders::{load_indexed_bmp, load_palette_cycles, load_xraw},
+        palette::cycled_indices,
+        rendering::Viewport
+    },
+    voxel_model::VoxelModel
+};
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 96;
+const BACKGROUND_COLOR_ID: u8 = 1;
+/// A few pixels may flip on platforms rounding floats slightly differently
+const MAX_DIFF_PIXELS: usize = 16;
+
+fn manifest_path(relative: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(relative)
+}
+
+fn spawn_camera(world: &mut World, pos: Vec3A, yaw_degrees: f32, pitch_degrees: f32) {
+    spawn_projected_camera(world, pos, yaw_degrees, pitch_degrees, Camera::new(Viewport::full_screen(WIDTH, HEIGHT)));
+}
+
+fn spawn_projected_camera(world: &mut World, pos: Vec3A, yaw_degrees: f32, pitch_degrees: f32, camera: Camera) {
+    world.spawn((
+        Position { value: pos },
+        ViewAngle { value: yaw_degrees.to_radians() },
+        ViewTilt { pitch: pitch_degrees.to_radians(), roll: 0.0 },
+        camera
+    ));
+}
+
+fn spawn_model(world: &mut World, pos: Vec3A, model: VoxelModel) {
+    world.spawn((Position { value: pos }, Voxel { data: model }));
+}
+
+fn render(world: &World, tracing_mode: TracingMode) -> (Vec<u8>, Vec<[u8; 3]>) {
+    let (palette, _) = retro_blit::format_loaders::im_256::Image::load_from(TILES_2D_BYTES).unwrap();
+    // shaded the way the game shades them
+    let cycled = cycled_indices(&load_palette_cycles(PALETTE_CYCLES));
+    let mut renderer = VoxelRenderer::new(&palette, &cycled, demo_materials(), BACKGROUND_COLOR_ID);
+    renderer.tracing_mode = tracing_mode;
+    let (frame, _) = renderer.render_offscreen(world, WIDTH, HEIGHT, BACKGROUND_COLOR_ID);
+    (frame, palette)
+}
+
+/// Renders `world` with every tracing mode and compares the frames against the reference `name`
+fn check_golden(name: &str, world: &World) {
+    let reference_path = manifest_path(&format!("src/assets/golden/{}.bmp", name));
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        let (frame, palette) = render(world, TracingMode::Scalar);
+        std::fs::create_dir_all(reference_path.parent().unwrap()).unwrap();
+        save_image(&reference_path, WIDTH, HEIGHT, &frame, &palette).unwrap();
+        return;
+    }
+
+    let bytes = std::fs::read(&reference_path)
+        .unwrap_or_else(|e| panic!("missing reference {}: {}", reference_path.display(), e));
+    let (width, height, reference) = load_indexed_bmp(&bytes);
+    assert_eq!((width, height), (WIDTH, HEIGHT), "reference {} has a wrong size", name);
+
+    for tracing_mode in [TracingMode::Scalar, TracingMode::Packet2x2] {
+        let (frame, palette) = render(world, tracing_mode);
+        let diff: Vec<u8> = frame.iter().zip(reference.iter()).map(|(a, b)| (a != b) as u8).collect();
+        let diff_count = diff.iter().filter(|&&d| d != 0).count();
+        if diff_count <= MAX_DIFF_PIXELS { continue; }
+
+        let out_dir = manifest_path("target/golden");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let actual_path = out_dir.join(format!("{}_{:?}.bmp", name, tracing_mode));
+        let diff_path = out_dir.join(format!("{}_{:?}_diff.bmp", name, tracing_mode));
+        save_image(&actual_path, WIDTH, HEIGHT, &frame, &palette).unwrap();
+        save_image(&diff_path, WIDTH, HEIGHT, &diff, &[[0, 0, 0], [255, 0, 64]]).unwrap();
+        panic!(
+            "{} ({:?}): {} of {} pixels differ from the reference, see {} and {}",
+            name, tracing_mode, diff_count, WIDTH * HEIGHT, actual_path.display(), diff_path.display()
+        );
+    }
+}
+
+fn demo_world() -> World {
+    let (_, tiles_2d) = retro_blit::format_loaders::im_256::Image::load_from(TILES_2D_BYTES).unwrap();
+    let mut world = World::new();
+    spawn_demo_scene(&mut world, &tiles_2d, &demo_materials());
+    world
+}
+
+#[test]
+fn golden_demo_scene() {
+    // as seen by the player when the game starts
+    check_golden("demo_player", &demo_world());
+
+    let mut world = demo_world();
+    for camera in world.view::<&mut Camera>() {
+        camera.active = false;
+    }
+    spawn_camera(&mut world, vec3a(0.0, 24.0, -16.0), 10.0, -35.0);
+    check_golden("demo_overview", &world);
+}
+
+#[test]
+fn golden_parallel_projections() {
+    let viewport = Viewport::full_screen(WIDTH, HEIGHT);
+
+    let mut world = demo_world();
+    for camera in world.view::<&mut Camera>() {
+        camera.active = false;
+    }
+    // rays start on the near plane in front of the camera, so it stays well away from the scene
+    let isometric = Camera { projection: Projection::Isometric, zoom: 1.5, ..Camera::new(viewport) };
+    spawn_projected_camera(&mut world, vec3a(-160.0, 120.0, -80.0), 0.0, 0.0, isometric);
+    check_golden("demo_isometric", &world);
+
+    let mut world = demo_world();
+    for camera in world.view::<&mut Camera>() {
+        camera.active = false;
+    }
+    let top_down = Camera { projection: Projection::TopDown, zoom: 0.75, ..Camera::new(viewport) };
+    spawn_projected_camera(&mut world, vec3a(0.0, 200.0, 96.0), 0.0, 0.0, top_down);
+    check_golden("demo_top_down", &world);
+}
+
+#[test]
+fn golden_subsampling() {
+    for (subsampling, name) in [
+        (Subsampling::Checkerboard, "demo_player_checkerboard"),
+        (Subsampling::Grid2x2, "demo_player_grid2x2")
+    ] {
+        let world = demo_world();
+        for camera in world.view::<&mut Camera>() {
+            camera.subsampling = subsampling;
+        }
+        check_golden(name, &world);
+    }
+}
+
+#[test]
+fn golden_sphere() {
+    let mut world = World::new();
+    spawn_model(&mut world, Vec3A::ZERO, VoxelModel::make_sphere32x32x32(0, 23));
+    spawn_camera(&mut world, vec3a(48.0, 40.0, -16.0), -45.0, -30.0);
+    check_golden("sphere", &world);
+}
+
+#[test]
+fn golden_grass_corner() {
+    let mut world = World::new();
+    spawn_model(&mut world, Vec3A::ZERO, load_xraw(GRASS_DIRT_CORNER_XRAW));
+    spawn_camera(&mut world, vec3a(-12.0, 40.0, -20.0), 35.0, -40.0);
+    check_golden("grass_corner", &world);
+}
@@ -0,0 +1,275 @@
+use edict::world::World;
+use retro_blit::{
+    rendering::{
+        blittable::{BufferProviderMut, SizedSurface},
+        fonts::{font_align::{HorizontalAlignment, VerticalAlignment}, tri_spaced::{Font, TextDrawer}}
+    },
+    window::RetroBlitContext
+};
+
+use crate::{
+    components::{Ammo, Camera, Health, PlayerTag, Position, SurfaceRegion, ViewAngle},
+    resources::{DebugOverlay, FrameTimings, HudMessages},
+    systems::{rendering::sprites::SpriteSheet, BaseSystem},
+    utils::{palette::assert_not_cycled, rendering::Viewport}
+};
+
+/// Height of a line of HUD text, bar rows included
+const LINE_HEIGHT: usize = 12;
+/// Height of a line of debug overlay text
+const DEBUG_LINE_HEIGHT: usize = 7;
+const LABEL_WIDTH: usize = 24;
+const BAR_WIDTH: usize = 64;
+const BAR_HEIGHT: usize = 4;
+/// How quickly the shown FPS follows the frame time, from 0 to 1
+const FPS_SMOOTHING: f32 = 0.1;
+
+/// Palette indices the HUD is drawn with
+#[derive(Clone, Copy, Debug)]
+pub struct HudStyle {
+    pub text: u8,
+    pub background: u8,
+    pub health_bar: u8,
+    pub ammo_bar: u8,
+    pub bar_back: u8,
+    pub debug_text: u8
+}
+
+impl Default for HudStyle {
+    /// Pale blue text over the dark blue the screen is cleared with, and red health and green
+    /// ammo bars over slate, picked from the `tiles2d.im256` palette
+    fn default() -> Self {
+        Self { text: 21, background: 1, health_bar: 28, ammo_bar: 10, bar_back: 25, debug_text: 20 }
+    }
+}
+
+impl HudStyle {
+    pub fn color_ids(&self) -> [u8; 6] {
+        [self.text, self.background, self.health_bar, self.ammo_bar, self.bar_back, self.debug_text]
+    }
+}
+
+/// Regions of the sprite sheet drawn in front of the bars
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HudIcons {
+    pub health: Option<SurfaceRegion>,
+    pub ammo: Option<SurfaceRegion>
+}
+
+/// Fills `rect` of `buffer`, a framebuffer `stride` pixels wide, clipping it to the framebuffer
+pub fn fill_rect(buffer: &mut [u8], stride: usize, rect: Viewport, color: u8) {
+    let height = buffer.len() / stride.max(1);
+    let (x_end, y_end) = ((rect.x + rect.width).min(stride), (rect.y + rect.height).min(height));
+    if rect.x >= x_end { return; }
+    for y in rect.y..y_end {
+        buffer[y * stride + rect.x..y * stride + x_end].fill(color);
+    }
+}
+
+/// Draws a bar filled from the left by `fraction` of its width, which is clamped to `[0, 1]`
+pub fn draw_bar(buffer: &mut [u8], stride: usize, rect: Viewport, fraction: f32, fill: u8, back: u8) {
+    let filled = (rect.width as f32 * fraction.clamp(0.0, 1.0)).round() as usize;
+    fill_rect(buffer, stride, rect, back);
+    fill_rect(buffer, stride, Viewport { width: filled, ..rect }, fill);
+}
+
+/// Copies `region` of the sprite sheet with its top left corner at `(x, y)`, skipping the
+/// transparent palette index 0 and whatever falls outside of the framebuffer
+pub fn draw_icon(buffer: &mut [u8], stride: usize, sheet: &SpriteSheet, region: SurfaceRegion, x: usize, y: usize) {
+    let height = buffer.len() / stride.max(1);
+    for j in 0..region.height.min(height.saturating_sub(y)) {
+        for i in 0..region.width.min(stride.saturating_sub(x)) {
+            let (sx, sy) = (region.x + i, region.y + j);
+            if sx >= sheet.width || sy >= sheet.height { continue; }
+            let color_id = sheet.pixels[sy * sheet.width + sx];
+            if color_id == 0 { continue; }
+            buffer[(y + j) * stride + x + i] = color_id;
+        }
+    }
+}
+
+/// Draws the status of the player into `area`, the 24 rows below the view by default, messages
+/// over the top of the view and, while the `DebugOverlay` resource is visible, FPS, camera pose
+/// and system timings over its top left corner
+pub struct HudSystem {
+    font: Font,
+    sheet: SpriteSheet,
+    pub area: Viewport,
+    pub style: HudStyle,
+    pub icons: HudIcons,
+    fps: f32
+}
+
+impl HudSystem {
+    /// The default style is checked against `cycled`, the [`cycled_indices`](crate::utils::palette::cycled_indices) of the palette
+    pub fn new(sheet: SpriteSheet, cycled: &[u8], area: Viewport) -> Self {
+        let style = HudStyle::default();
+        assert_not_cycled("the HUD", &style.color_ids(), cycled);
+        Self {
+            font: Font::default_font_small().unwrap(),
+            sheet,
+            area,
+            style,
+            icons: HudIcons::default(),
+            fps: 0.0
+        }
+    }
+
+    fn draw_text(&self, ctx: &mut RetroBlitContext, rect: Viewport, alignment: HorizontalAlignment, text: &str, color: u8) {
+        self.font.draw_text_in_box(
+            ctx,
+            rect.x as i32, rect.y as i32,
+            rect.width as i32, rect.height as i32,
+            alignment,
+            VerticalAlignment::Center,
+            text,
+            Some(color)
+        );
+    }
+
+    /// Draws a labelled bar on line `line` of the HUD area
+    #[allow(clippy::too_many_arguments)]
+    fn draw_stat(&self, ctx: &mut RetroBlitContext, line: usize, icon: Option<SurfaceRegion>, label: &str, fraction: f32, value: &str, fill: u8) {
+        let stride = ctx.get_width();
+        let y = self.area.y + line * LINE_HEIGHT;
+        let mut x = self.area.x + 2;
+        if let Some(icon) = icon {
+            draw_icon(ctx.get_buffer_mut(), stride, &self.sheet, icon, x, y + LINE_HEIGHT.saturating_sub(icon.height) / 2);
+            x += icon.width + 2;
+        }
+
+        self.draw_text(ctx, Viewport { x, y, width: LABEL_WIDTH, height: LINE_HEIGHT }, HorizontalAlignment::Left, label, self.style.text);
+        let bar = Viewport { x: x + LABEL_WIDTH, y: y + (LINE_HEIGHT - BAR_HEIGHT) / 2, width: BAR_WIDTH, height: BAR_HEIGHT };
+        draw_bar(ctx.get_buffer_mut(), stride, bar, fraction, fill, self.style.bar_back);
+
+        let value_x = bar.x + BAR_WIDTH + 4;
+        let value_width = (self.area.x + self.area.width).saturating_sub(value_x);
+        self.draw_text(ctx, Viewport { x: value_x, y, width: value_width, height: LINE_HEIGHT }, HorizontalAlignment::Left, value, self.style.text);
+    }
+
+    fn draw_debug_overlay(&self, ctx: &mut RetroBlitContext, world: &mut World) {
+        let mut lines = vec![format!("FPS {:.0}", self.fps)];
+
+        let camera = world.view::<(&Camera, &Position, &ViewAngle)>()
+            .into_iter()
+            .find(|(camera, ..)| camera.active)
+            .map(|(_, pos, angle)| (pos.value, angle.value));
+        if let Some((pos, angle)) = camera {
+            lines.push(format!("POS {:.0} {:.0} {:.0}", pos.x, pos.y, pos.z));
+            lines.push(format!("ANG {:.0}", angle.to_degrees().rem_euclid(360.0)));
+        }
+
+        if let Some(timings) = world.remove_resource::<FrameTimings>() {
+            for (name, duration) in timings.entries.iter() {
+                lines.push(format!("{} {:.1}MS", name.to_uppercase(), duration.as_secs_f32() * 1000.0));
+            }
+            world.insert_resource(timings);
+        }
+
+        for (ix, line) in lines.iter().enumerate() {
+            let rect = Viewport { x: 2, y: 2 + ix * DEBUG_LINE_HEIGHT, width: 96, height: DEBUG_LINE_HEIGHT };
+            self.draw_text(ctx, rect, HorizontalAlignment::Left, line, self.style.debug_text);
+        }
+    }
+}
+
+impl BaseSystem for HudSystem {
+    fn run(&mut self, ctx: &mut RetroBlitContext, world: &mut World, dt: f32) {
+        if dt > 0.0 {
+            self.fps += (1.0 / dt - self.fps) * FPS_SMOOTHING;
+        }
+
+        let stride = ctx.get_width();
+        fill_rect(ctx.get_buffer_mut(), stride, self.area, self.style.background);
+
+        let player = world.view::<(&PlayerTag, Option<&Health>, Option<&Ammo>)>()
+            .into_iter()
+            .next()
+            .map(|(_, health, ammo)| (health.copied(), ammo.copied()));
+        let (health, ammo) = player.unwrap_or((None, None));
+
+        let mut line = 0;
+        if let Some(health) = health {
+            let value = format!("{:.0}/{:.0}", health.current.max(0.0), health.max);
+            let fraction = health.current / health.max.max(f32::EPSILON);
+            self.draw_stat(ctx, line, self.icons.health, "HP", fraction, &value, self.style.health_bar);
+            line += 1;
+        }
+        if let Some(ammo) = ammo {
+            let value = format!("{}/{}", ammo.current, ammo.max);
+            let fraction = ammo.current as f32 / ammo.max.max(1) as f32;
+            self.draw_stat(ctx, line, self.icons.ammo, "AMMO", fraction, &value, self.style.ammo_bar);
+        }
+
+        if let Some(mut messages) = world.remove_resource::<HudMessages>() {
+            messages.tick(dt);
+            // the newest messages on top of the view, as many as fit above the HUD area
+            let shown = messages.messages.len().min(self.area.y / LINE_HEIGHT).min(2);
+            for (ix, message) in messages.messages[messages.messages.len() - shown..].iter().enumerate() {
+                let rect = Viewport { x: 0, y: ix * LINE_HEIGHT, width: stride, height: LINE_HEIGHT };
+                self.draw_text(ctx, rect, HorizontalAlignment::Center, &message.text, self.style.text);
+            }
+            world.insert_resource(messages);
+        }
+
+        let debug = world.remove_resource::<DebugOverlay>();
+        if let Some(debug) = debug {
+            if debug.visible {
+                self.draw_debug_overlay(ctx, world);
+            }
+            world.insert_resource(debug);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        components::SurfaceRegion,
+        resources::{FrameTimings, HudMessages},
+        systems::rendering::sprites::SpriteSheet,
+        utils::rendering::Viewport
+    };
+
+    use super::{draw_bar, draw_icon};
+
+    #[test]
+    fn test_bars_and_icons() {
+        let mut buffer = vec![0; 16 * 8];
+        let rect = Viewport { x: 2, y: 1, width: 10, height: 2 };
+        draw_bar(&mut buffer, 16, rect, 0.5, 7, 3);
+        assert_eq!(&buffer[16 + 2..16 + 12], &[7, 7, 7, 7, 7, 3, 3, 3, 3, 3]);
+        assert_eq!(buffer[2 * 16 + 6], 7);
+        assert_eq!(buffer.iter().filter(|&&clr| clr != 0).count(), 20);
+
+        draw_bar(&mut buffer, 16, rect, 1.5, 4, 3);
+        assert!(buffer[16 + 2..16 + 12].iter().all(|&clr| clr == 4));
+
+        // a checkered icon hanging over the bottom right corner
+        let sheet = SpriteSheet { width: 4, height: 4, pixels: (0..16).map(|ix| ((ix + ix / 4) % 2) as u8 * 9).collect() };
+        let mut buffer = vec![1; 16 * 8];
+        draw_icon(&mut buffer, 16, &sheet, SurfaceRegion { x: 0, y: 0, width: 4, height: 4 }, 14, 6);
+        assert_eq!(&buffer[6 * 16 + 14..7 * 16], &[1, 9]);
+        assert_eq!(&buffer[7 * 16 + 14..8 * 16], &[9, 1]);
+        assert_eq!(buffer.iter().filter(|&&clr| clr == 9).count(), 2);
+    }
+
+    #[test]
+    fn test_hud_resources() {
+        let mut messages = HudMessages::default();
+        messages.push("found a key", 1.0);
+        messages.push("door opened", 3.0);
+        messages.tick(0.5);
+        assert_eq!(messages.messages.len(), 2);
+        messages.tick(1.0);
+        assert_eq!(messages.messages.iter().map(|m| m.text.as_str()).collect::<Vec<_>>(), vec!["door opened"]);
+
+        let mut timings = FrameTimings::default();
+        timings.record("voxels", Duration::from_millis(5));
+        timings.record("sprites", Duration::from_millis(1));
+        timings.record("voxels", Duration::from_millis(4));
+        assert_eq!(timings.entries, vec![("voxels", Duration::from_millis(4)), ("sprites", Duration::from_millis(1))]);
+    }
+}
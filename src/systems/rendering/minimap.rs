@@ -0,0 +1,299 @@
+use edict::world::World;
+use glam::{vec3a, Vec3A};
+use retro_blit::{
+    rendering::blittable::{BufferProviderMut, SizedSurface},
+    window::RetroBlitContext
+};
+
+use crate::{
+    components::{Camera, PlayerTag, Position, ViewAngle},
+    resources::{CellTop, ExploredCells},
+    systems::{rendering::hud::fill_rect, BaseSystem},
+    utils::{
+        bvh::EntityBvh,
+        palette::{assert_not_cycled, LightTable, LIGHT_LEVELS},
+        rendering::{camera_basis, Viewport}
+    }
+};
+
+/// Rays cast across the view of the player each frame to find the cells they see
+const SIGHT_RAYS: usize = 24;
+/// Length of the view direction marker, in minimap pixels
+const MARKER_LENGTH: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MinimapMode {
+    /// Colors of the first voxels below a cut just above the player, which hides cave ceilings
+    Slice,
+    /// The same voxels, darker the further below the cut they lie
+    HeightShaded
+}
+
+/// Palette indices the minimap is drawn with
+#[derive(Clone, Copy, Debug)]
+pub struct MinimapStyle {
+    pub unexplored: u8,
+    /// explored cells without any voxel below the cut
+    pub empty: u8,
+    pub border: u8,
+    pub marker: u8
+}
+
+impl Default for MinimapStyle {
+    /// Unexplored cells in the color the screen is cleared with and empty ones a shade lighter,
+    /// framed in slate, with a skin colored marker standing out from both
+    fn default() -> Self {
+        Self { unexplored: 1, empty: 15, border: 22, marker: 7 }
+    }
+}
+
+impl MinimapStyle {
+    pub fn color_ids(&self) -> [u8; 4] {
+        [self.unexplored, self.empty, self.border, self.marker]
+    }
+}
+
+/// Draws a top-down map of the world around the player into `area`, north (+Z) up, with the
+/// player in the middle and a marker pointing along their `ViewAngle`. Cells only show up once
+/// the player has seen them, which is tracked in the `ExploredCells` resource along with what
+/// they show. The map is drawn from there, and the scene only gets traced again for new cells
+/// once the player moves or turns.
+///
+/// The map shows the layer of cells holding the point `cut_above` over the player, each showing
+/// the first voxel below its bottom. Cuts thus lie up to `ExploredCells::LAYER_HEIGHT` below that
+/// point, and stay put while the player walks around a floor.
+pub struct MinimapSystem {
+    pub area: Viewport,
+    pub mode: MinimapMode,
+    /// voxels per minimap pixel
+    pub scale: f32,
+    /// how far above the player the layer of cells shown lies
+    pub cut_above: f32,
+    /// how far the player sees when revealing cells
+    pub sight_range: f32,
+    /// depth below the cut at which height shading gets darkest
+    pub shading_depth: f32,
+    pub style: MinimapStyle,
+    light_table: LightTable,
+    /// position, yaw and field of view cells were last revealed from
+    last_view: Option<(Vec3A, f32, f32)>
+}
+
+impl MinimapSystem {
+    /// `cycled` are the [`cycled_indices`](crate::utils::palette::cycled_indices) of the palette, which height shading keeps clear of
+    /// and the default style is checked against
+    pub fn new(palette: &[[u8; 3]], cycled: &[u8], area: Viewport) -> Self {
+        let style = MinimapStyle::default();
+        assert_not_cycled("the minimap", &style.color_ids(), cycled);
+        Self {
+            area,
+            mode: MinimapMode::HeightShaded,
+            scale: 4.0,
+            cut_above: 16.0,
+            sight_range: 128.0,
+            shading_depth: 64.0,
+            style,
+            light_table: LightTable::new(palette, cycled),
+            last_view: None
+        }
+    }
+
+    /// Point of the cell shown for a player at `pos`
+    fn cut_point(&self, pos: Vec3A) -> Vec3A {
+        pos + Vec3A::Y * self.cut_above
+    }
+
+    /// Reveals the cells seen from `pos` looking along `yaw` with a horizontal field of view of
+    /// `fov_slope`, up to the first voxel each sight ray hits. New cells keep the first voxel
+    /// below them.
+    pub fn explore(&self, bvh: &EntityBvh, explored: &mut ExploredCells, pos: Vec3A, yaw: f32, fov_slope: f32) {
+        let top = |bottom| bvh.cast_ray(bottom, -Vec3A::Y).map(|hit| CellTop { color_id: hit.color_id, depth: hit.t });
+        explored.reveal(self.cut_point(pos), top);
+        let basis = camera_basis(yaw, 0.0, 0.0);
+        let step = ExploredCells::CELL_SIZE * 0.5;
+
+        for ray in 0..SIGHT_RAYS {
+            let slope = fov_slope * (ray as f32 / (SIGHT_RAYS - 1) as f32 * 2.0 - 1.0);
+            let dir = (basis.x_axis * slope + basis.z_axis).normalize();
+            let distance = bvh.cast_ray(pos, dir).map_or(self.sight_range, |hit| hit.t.min(self.sight_range));
+
+            let steps = (distance / step) as usize;
+            for ix in 0..=steps {
+                explored.reveal(self.cut_point(pos + dir * (ix as f32 * step)), top);
+            }
+            // the cell of the wall the ray stopped at is seen as well
+            explored.reveal(self.cut_point(pos + dir * (distance + step * 0.5)), top);
+        }
+    }
+
+    /// World position shown at pixel `(i, j)` of the map, at the height of the cut above `pos`
+    fn cell_center(&self, pos: Vec3A, i: usize, j: usize) -> Vec3A {
+        let (cx, cy) = (self.area.width as f32 * 0.5, self.area.height as f32 * 0.5);
+        let cut = self.cut_point(pos);
+        vec3a(cut.x + (i as f32 + 0.5 - cx) * self.scale, cut.y, cut.z - (j as f32 + 0.5 - cy) * self.scale)
+    }
+
+    /// Draws the map centered on the player at `pos` into `buffer`, a framebuffer `stride` pixels wide
+    pub fn draw(&self, explored: &ExploredCells, pos: Vec3A, yaw: f32, buffer: &mut [u8], stride: usize) {
+        let area = self.area;
+        if !area.fits_into(stride, buffer.len() / stride.max(1)) || area.width < 3 || area.height < 3 { return; }
+
+        fill_rect(buffer, stride, area, self.style.border);
+        for j in 1..area.height - 1 {
+            for i in 1..area.width - 1 {
+                let color_id = match explored.get(self.cell_center(pos, i, j)) {
+                    None => self.style.unexplored,
+                    Some(None) => self.style.empty,
+                    Some(Some(top)) => match self.mode {
+                        MinimapMode::Slice => top.color_id,
+                        MinimapMode::HeightShaded => {
+                            let depth = (top.depth / self.shading_depth).clamp(0.0, 1.0);
+                            let level = (LIGHT_LEVELS - 1) - (depth * (LIGHT_LEVELS / 2) as f32) as usize;
+                            self.light_table.get(top.color_id, level)
+                        }
+                    }
+                };
+                buffer[(area.y + j) * stride + area.x + i] = color_id;
+            }
+        }
+
+        // the player in the middle, with a short line along the view direction
+        let forward = camera_basis(yaw, 0.0, 0.0).z_axis;
+        let (cx, cy) = (area.width as f32 * 0.5, area.height as f32 * 0.5);
+        for step in 0..=MARKER_LENGTH {
+            let (i, j) = (cx + forward.x * step as f32, cy - forward.z * step as f32);
+            if i < 1.0 || j < 1.0 || i >= (area.width - 1) as f32 || j >= (area.height - 1) as f32 { continue; }
+            buffer[(area.y + j as usize) * stride + area.x + i as usize] = self.style.marker;
+        }
+    }
+}
+
+impl BaseSystem for MinimapSystem {
+    fn run(&mut self, ctx: &mut RetroBlitContext, world: &mut World, _dt: f32) {
+        let player = world.view::<(&PlayerTag, &Position, &ViewAngle, Option<&Camera>)>()
+            .into_iter()
+            .next()
+            .map(|(_, pos, angle, camera)| (pos.value, angle.value, camera.map_or(1.0, |camera| camera.fov_slope / camera.zoom)));
+        let Some((pos, yaw, fov_slope)) = player else { return; };
+
+        let mut explored = world.remove_resource::<ExploredCells>().unwrap_or_default();
+        // nothing new comes into view while the player stands still, unless the cells got cleared
+        let view = (pos, yaw, fov_slope);
+        if self.last_view != Some(view) || !explored.is_revealed(self.cut_point(pos)) {
+            let bvh = EntityBvh::from_world(world);
+            self.explore(&bvh, &mut explored, pos, yaw, fov_slope);
+            self.last_view = Some(view);
+        }
+
+        let stride = ctx.get_width();
+        self.draw(&explored, pos, yaw, ctx.get_buffer_mut(), stride);
+        world.insert_resource(explored);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use glam::{vec3a, Vec3A};
+
+    use crate::{
+        resources::{CellTop, ExploredCells},
+        utils::{bvh::EntityBvh, rendering::Viewport},
+        voxel_model::VoxelModel
+    };
+
+    use super::{MinimapMode, MinimapSystem};
+
+    #[test]
+    fn test_explored_cells() {
+        let mut explored = ExploredCells::default();
+        assert_eq!(ExploredCells::cell_of(vec3a(-0.5, 100.0, 7.9)), (-1, 6, 1));
+        let top = CellTop { color_id: 3, depth: 8.0 };
+        explored.reveal(vec3a(1.0, 20.0, 1.0), |bottom| {
+            assert_eq!(bottom, vec3a(2.0, 16.0, 2.0));
+            Some(top)
+        });
+        // cells already revealed keep what they show
+        explored.reveal(vec3a(3.0, 30.0, 3.0), |_| unreachable!());
+        assert_eq!(explored.get(vec3a(0.5, 16.0, 3.5)), Some(Some(top)));
+        assert_eq!(explored.get(vec3a(4.5, 20.0, 0.5)), None);
+        assert!(explored.is_revealed(vec3a(3.9, 31.0, 0.0)));
+        assert!(!explored.is_revealed(vec3a(-0.1, 20.0, 0.0)));
+        // the layers above and below are explored on their own
+        assert!(!explored.is_revealed(vec3a(1.0, 4.0, 1.0)));
+        explored.reveal(vec3a(1.0, 4.0, 1.0), |_| None);
+        assert_eq!(explored.get(vec3a(1.0, 0.0, 1.0)), Some(None));
+        assert_eq!(explored.get(vec3a(1.0, 32.0, 1.0)), None);
+        explored.clear();
+        assert!(!explored.is_revealed(vec3a(1.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn test_minimap() {
+        // a floor with a wall across it at z = 64, and a deck high above the floor
+        let floor = VoxelModel::make_32x32x32(|_, y, _| if y < 4 { 9 } else { 0 });
+        let wall = VoxelModel::make_32x32x32(|_, _, z| if z < 4 { 23 } else { 0 });
+        let deck = VoxelModel::make_32x32x32(|_, y, _| if y < 4 { 12 } else { 0 });
+        let mut items: Vec<(Vec3A, &VoxelModel)> = (0..16)
+            .map(|ix| (vec3a((ix % 4) as f32 * 32.0 - 64.0, 0.0, (ix / 4) as f32 * 32.0 - 32.0), &floor))
+            .collect();
+        items.extend((0..4).map(|ix| (vec3a(ix as f32 * 32.0 - 64.0, 0.0, 64.0), &wall)));
+        items.extend((0..4).map(|ix| (vec3a(ix as f32 * 32.0 - 64.0, 64.0, 0.0), &deck)));
+        let bvh = EntityBvh::build(items);
+
+        let palette: Vec<[u8; 3]> = (0..32).map(|i| [i * 8; 3]).collect();
+        let mut minimap = MinimapSystem::new(&palette, &[], Viewport { x: 4, y: 2, width: 40, height: 40 });
+        minimap.mode = MinimapMode::Slice;
+
+        // looking north at the wall, with the map cut through the layer of cells from y = 32 to 48
+        let pos = vec3a(0.0, 16.0, 16.0);
+        let cut = minimap.cut_point(pos);
+        let mut explored = ExploredCells::default();
+        minimap.explore(&bvh, &mut explored, pos, 0.0, 1.0);
+        assert!(explored.is_revealed(cut + Vec3A::Z * 32.0));
+        assert!(explored.is_revealed(cut + Vec3A::Z * 40.0 + Vec3A::X * 4.0));
+        // behind the wall and behind the player stay hidden
+        assert!(!explored.is_revealed(cut + Vec3A::Z * 64.0));
+        assert!(!explored.is_revealed(cut - Vec3A::Z * 16.0));
+        // the floor below the revealed cells is looked up once, the map is drawn from there
+        assert_eq!(explored.get(cut + Vec3A::Z * 32.0), Some(Some(CellTop { color_id: 9, depth: 28.0 })));
+
+        let mut buffer = vec![255; 48 * 48];
+        minimap.draw(&explored, pos, 0.0, &mut buffer, 48);
+        let pixel = |buffer: &[u8], i: usize, j: usize| buffer[(2 + j) * 48 + 4 + i];
+        let style = minimap.style;
+
+        assert_eq!(pixel(&buffer, 0, 0), style.border);
+        // the marker points up the map, with floor ahead of it, the wall further up and nothing seen below
+        assert_eq!(pixel(&buffer, 20, 20), style.marker);
+        assert_eq!(pixel(&buffer, 20, 18), style.marker);
+        assert_eq!(pixel(&buffer, 20, 14), 9);
+        assert_eq!(pixel(&buffer, 20, 7), 23);
+        assert_eq!(pixel(&buffer, 20, 30), style.unexplored);
+        assert_eq!(buffer[0], 255);
+
+        // height shading darkens the floor below the cut
+        minimap.mode = MinimapMode::HeightShaded;
+        minimap.draw(&explored, pos, 0.0, &mut buffer, 48);
+        assert_ne!(pixel(&buffer, 20, 14), 9);
+
+        // zooming out keeps what has been seen
+        minimap.mode = MinimapMode::Slice;
+        minimap.scale = 8.0;
+        minimap.draw(&explored, pos, 0.0, &mut buffer, 48);
+        assert_eq!(pixel(&buffer, 20, 16), 9);
+        assert_eq!(pixel(&buffer, 20, 26), style.unexplored);
+
+        // up on the deck the cells of its own layer get revealed, the floor below keeps its map
+        let upper = pos + Vec3A::Y * 64.0;
+        minimap.explore(&bvh, &mut explored, upper, 0.0, 1.0);
+        let upper_cut = minimap.cut_point(upper);
+        assert_eq!(explored.get(upper_cut + Vec3A::Z * 8.0), Some(Some(CellTop { color_id: 12, depth: 28.0 })));
+        assert!(explored.is_revealed(upper_cut + Vec3A::Z * 64.0));
+        assert!(!explored.is_revealed(cut + Vec3A::Z * 64.0));
+        minimap.scale = 4.0;
+        minimap.draw(&explored, upper, 0.0, &mut buffer, 48);
+        assert_eq!(pixel(&buffer, 22, 16), 12);
+        minimap.draw(&explored, pos, 0.0, &mut buffer, 48);
+        assert_eq!(pixel(&buffer, 22, 16), 9);
+    }
+}
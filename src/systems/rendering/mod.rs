@@ -2,8 +2,17 @@ use edict::world::World;
 use retro_blit::window::RetroBlitContext;
 use super::BaseSystem;
 
+pub mod hud;
+pub mod minimap;
+pub mod palette_animation;
+pub mod shading;
+pub mod sprites;
+pub mod subsampling;
 pub mod voxels;
 
+#[cfg(test)]
+mod golden;
+
 pub struct ClearScreenSystem(pub u8);
 
 impl BaseSystem for ClearScreenSystem {
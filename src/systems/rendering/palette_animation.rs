@@ -0,0 +1,105 @@
+use edict::world::World;
+use retro_blit::window::RetroBlitContext;
+
+use crate::{systems::BaseSystem, utils::palette::PaletteCycle};
+
+/// Classic palette cycling: colors of the configured cycles are rotated over time, so tiles
+/// drawn with those entries animate without touching voxel data
+pub struct PaletteAnimationSystem {
+    base_palette: Vec<[u8; 3]>,
+    cycles: Vec<PaletteCycle>,
+    offsets: Vec<usize>,
+    /// how many steps each cycle has moved, wrapped to its length so it stays precise however
+    /// long the game runs
+    phases: Vec<f32>
+}
+
+impl PaletteAnimationSystem {
+    pub fn new(base_palette: &[[u8; 3]], cycles: Vec<PaletteCycle>) -> Self {
+        let (offsets, phases) = (vec![0; cycles.len()], vec![0.0; cycles.len()]);
+        Self { base_palette: base_palette.to_vec(), cycles, offsets, phases }
+    }
+
+    /// Moves the animation `dt` seconds forward, returning the palette entries which changed
+    pub fn advance(&mut self, dt: f32) -> Vec<(u8, [u8; 3])> {
+        let mut changes = Vec::new();
+        for ((cycle, phase), last_offset) in self.cycles.iter().zip(self.phases.iter_mut()).zip(self.offsets.iter_mut()) {
+            let len = cycle.indices.len();
+            if len == 0 { continue; }
+            *phase = (*phase + dt * cycle.speed).rem_euclid(len as f32);
+            let offset = *phase as usize % len;
+            if offset == *last_offset { continue; }
+            *last_offset = offset;
+
+            for (k, &ix) in cycle.indices.iter().enumerate() {
+                let source = cycle.indices[(k + offset) % len] as usize;
+                let Some(&color) = self.base_palette.get(source) else { continue; };
+                changes.push((ix, color));
+            }
+        }
+        changes
+    }
+}
+
+impl BaseSystem for PaletteAnimationSystem {
+    fn run(&mut self, ctx: &mut RetroBlitContext, _world: &mut World, dt: f32) {
+        for (ix, color) in self.advance(dt) {
+            ctx.set_palette(ix, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        scenes::PALETTE_CYCLES,
+        systems::rendering::{hud::HudStyle, minimap::MinimapStyle},
+        utils::{loaders::load_palette_cycles, palette::{assert_not_cycled, cycled_indices, PaletteCycle}}
+    };
+
+    use super::PaletteAnimationSystem;
+
+    #[test]
+    fn test_load_palette_cycles() {
+        let cycles = load_palette_cycles("# comment\n\n  glow 1.5 3 4 5\nflip -2 0 255\n");
+        assert_eq!(cycles, vec![
+            PaletteCycle { name: "glow".to_string(), speed: 1.5, indices: vec![3, 4, 5] },
+            PaletteCycle { name: "flip".to_string(), speed: -2.0, indices: vec![0, 255] }
+        ]);
+
+        let shipped = load_palette_cycles(PALETTE_CYCLES);
+        assert!(shipped.iter().any(|cycle| cycle.name == "lava"));
+        assert!(shipped.iter().all(|cycle| cycle.indices.len() > 1 && cycle.speed != 0.0));
+    }
+
+    #[test]
+    fn test_default_styles_not_cycled() {
+        let cycled = cycled_indices(&load_palette_cycles(PALETTE_CYCLES));
+        assert_not_cycled("the HUD", &HudStyle::default().color_ids(), &cycled);
+        assert_not_cycled("the minimap", &MinimapStyle::default().color_ids(), &cycled);
+    }
+
+    #[test]
+    fn test_palette_cycling() {
+        let palette: Vec<[u8; 3]> = (0..8).map(|i| [i * 10; 3]).collect();
+        let cycles = vec![
+            PaletteCycle { name: "forward".to_string(), speed: 2.0, indices: vec![1, 2, 5] },
+            PaletteCycle { name: "backward".to_string(), speed: -1.0, indices: vec![6, 7] }
+        ];
+        let mut animation = PaletteAnimationSystem::new(&palette, cycles);
+
+        // running backwards steps as soon as the time starts moving
+        assert_eq!(animation.advance(0.25), vec![(6, [70; 3]), (7, [60; 3])]);
+        assert_eq!(animation.advance(0.25), vec![(1, [20; 3]), (2, [50; 3]), (5, [10; 3])]);
+        assert_eq!(animation.advance(0.5), vec![(1, [50; 3]), (2, [10; 3]), (5, [20; 3])]);
+
+        // a full turn of both cycles restores the original colors
+        let changes = animation.advance(0.5);
+        assert_eq!(changes, vec![(1, [10; 3]), (2, [20; 3]), (5, [50; 3]), (6, [60; 3]), (7, [70; 3])]);
+
+        // half a step still moves the cycles after running for ages
+        animation.advance(1.0e9);
+        assert!(animation.phases.iter().zip(animation.cycles.iter()).all(|(&phase, cycle)| phase < cycle.indices.len() as f32));
+        assert!(!animation.advance(0.5).is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+
+use glam::{vec3a, Vec3A};
+
+use crate::{
+    components::PointLight,
+    utils::{
+        bvh::EntityBvh,
+        materials::MaterialTable,
+        palette::{BlendTable, Colormap, LightTable, LIGHT_LEVELS},
+        ray_queries::RayHit,
+        rendering::PIXELS_PER_METER
+    }
+};
+
+pub const FOG_LEVELS: usize = 16;
+pub const TINT_LEVELS: usize = 8;
+
+/// How far towards its color the strongest light may tint a surface
+const MAX_TINT: f32 = 0.5;
+/// Shadow rays start this far off the surface, so they do not hit the voxel they leave
+const SHADOW_BIAS: f32 = 0.01;
+
+/// 4x4 Bayer matrix, thresholds for ordered dithering between neighbouring colormap rows
+const DITHER: [[f32; 4]; 4] = [
+    [0.0 / 16.0, 8.0 / 16.0, 2.0 / 16.0, 10.0 / 16.0],
+    [12.0 / 16.0, 4.0 / 16.0, 14.0 / 16.0, 6.0 / 16.0],
+    [3.0 / 16.0, 11.0 / 16.0, 1.0 / 16.0, 9.0 / 16.0],
+    [15.0 / 16.0, 7.0 / 16.0, 13.0 / 16.0, 5.0 / 16.0]
+];
+
+/// Depth based fog fading hits towards a fog color from `start` until they fully blend into it at
+/// the far plane of the camera they are seen by
+pub struct Fog {
+    pub start: f32,
+    colormap: Colormap
+}
+
+impl Fog {
+    pub fn new(palette: &[[u8; 3]], excluded: &[u8], fog_color_id: u8, start: f32) -> Self {
+        let target = palette.get(fog_color_id as usize).copied().unwrap_or([0, 0, 0]);
+        Self { start, colormap: Colormap::fade(palette, excluded, target, FOG_LEVELS) }
+    }
+
+    /// Fogged `color_id` for a hit `t` away seen by a camera whose far plane is `far` away,
+    /// dithered by the screen position of its pixel
+    #[inline(always)]
+    pub fn apply(&self, color_id: u8, t: f32, far: f32, x: usize, y: usize) -> u8 {
+        if t <= self.start { return color_id; }
+
+        let amount = ((t - self.start) / (far - self.start)).min(1.0);
+        let row = amount * (FOG_LEVELS - 1) as f32 + DITHER[y % 4][x % 4];
+        self.colormap.get(color_id, row as usize)
+    }
+}
+
+/// A [`PointLight`] placed in the scene
+#[derive(Clone, Copy)]
+pub struct LightInstance {
+    pub pos: Vec3A,
+    pub light: PointLight
+}
+
+/// `ray` mirrored about the face its `hit` entered through, starting just off the surface.
+/// Hits from inside a voxel have no face to mirror about.
+pub fn reflected_ray((ray_origin, ray_dir): (Vec3A, Vec3A), hit: &RayHit) -> Option<(Vec3A, Vec3A)> {
+    if hit.normal == Vec3A::ZERO { return None; }
+
+    let point = ray_origin + ray_dir * hit.t;
+    let dir = ray_dir - hit.normal * 2.0 * ray_dir.dot(hit.normal);
+    Some((point + hit.normal * SHADOW_BIAS, dir))
+}
+
+/// Light gathered from point lights at a single hit
+#[derive(Clone, Copy, Default)]
+pub struct LightSum {
+    pub brightness: f32,
+    /// `color_ramp` of the strongest light along with its contribution
+    pub tint: Option<(u8, f32)>,
+    /// share of the ambient and directional light taken away by [`AmbientOcclusion`], from 0 to 1
+    pub occlusion: f32
+}
+
+/// Darkens hits with solid voxels close around them, which brings out corners and the inside of
+/// caves. A few short rays are cast into the hemisphere above the hit, the same way shadow rays are.
+#[derive(Clone, Copy, Debug)]
+pub struct AmbientOcclusion {
+    /// how far the occlusion rays reach
+    pub radius: f32,
+    /// share of the light taken away when every ray is blocked, from 0 to 1
+    pub strength: f32
+}
+
+impl AmbientOcclusion {
+    /// Occlusion of `point` on a voxel face with `normal`, from 0 to `strength`
+    pub fn occlusion(&self, bvh: &EntityBvh, point: Vec3A, normal: Vec3A) -> f32 {
+        // hits from inside a voxel have no hemisphere to look into
+        if normal == Vec3A::ZERO { return 0.0; }
+
+        let (tangent, bitangent) = normal.any_orthonormal_pair();
+        let origin = point + normal * SHADOW_BIAS;
+        let blocked = [tangent, -tangent, bitangent, -bitangent]
+            .into_iter()
+            .filter(|&side| bvh.is_occluded(origin, (normal + side).normalize(), self.radius))
+            .count();
+        self.strength * blocked as f32 / 4.0
+    }
+}
+
+impl LightSum {
+    /// Sums the lights reaching `point` on a voxel face with `normal`, skipping those a shadow ray finds occluded
+    pub fn gather(bvh: &EntityBvh, lights: &[LightInstance], point: Vec3A, normal: Vec3A) -> Self {
+        let mut sum = Self::default();
+
+        for instance in lights {
+            let to_light = instance.pos - point;
+            let distance = to_light.length();
+            if distance >= instance.light.radius || distance == 0.0 { continue; }
+
+            let dir = to_light / distance;
+            // hits from inside a voxel have no normal, so they take the light head on
+            let facing = if normal == Vec3A::ZERO { 1.0 } else { normal.dot(dir) };
+            if facing <= 0.0 { continue; }
+
+            let falloff = 1.0 - distance / instance.light.radius;
+            let contribution = instance.light.intensity * falloff * falloff * facing;
+            if contribution <= 0.0 { continue; }
+
+            let origin = point + normal * SHADOW_BIAS;
+            if bvh.is_occluded(origin, dir, distance - SHADOW_BIAS) { continue; }
+
+            sum.brightness += contribution;
+            if !matches!(sum.tint, Some((_, strongest)) if strongest >= contribution) {
+                sum.tint = Some((instance.light.color_ramp, contribution));
+            }
+        }
+        sum
+    }
+}
+
+/// Directional lighting of voxel faces plus point lights, quantized to the levels of a palette
+/// [`LightTable`], followed by distance fog. Emissive materials and cycled entries skip all of it,
+/// the latter so they keep animating.
+pub struct Shading {
+    pub light_table: LightTable,
+    pub materials: MaterialTable,
+    /// lays translucent materials over what is behind them and mixes in reflections
+    pub blend_table: BlendTable,
+    /// how many times a ray may be mirrored off reflective materials
+    pub max_bounces: usize,
+    /// unit vector pointing towards the light
+    pub to_light: Vec3A,
+    /// brightness of faces turned away from the light, from 0 to 1
+    pub ambient: f32,
+    pub fog: Fog,
+    /// off by default, as it casts four more rays for every hit drawn, reflected ones included,
+    /// which is more than the shadow rays cost on the low-end machines subsampling is there for
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+    palette: Vec<[u8; 3]>,
+    /// palette entries the tables never shade into
+    cycled: Vec<u8>,
+    /// colormaps fading towards the `color_ramp` of point lights, built on demand
+    tints: HashMap<u8, Colormap>
+}
+
+impl Shading {
+    /// `fog_color_id` is best kept the same as the color the screen is cleared with,
+    /// so distant voxels fade into the background. `cycled` are the [`cycled_indices`](crate::utils::palette::cycled_indices) of the palette.
+    pub fn new(palette: &[[u8; 3]], cycled: &[u8], materials: MaterialTable, fog_color_id: u8) -> Self {
+        Self {
+            light_table: LightTable::new(palette, cycled),
+            materials,
+            blend_table: BlendTable::new(palette, cycled, 0.5),
+            max_bounces: 1,
+            to_light: vec3a(-0.4, 1.0, -0.6).normalize(),
+            ambient: 0.45,
+            fog: Fog::new(palette, cycled, fog_color_id, 0.25 * PIXELS_PER_METER),
+            ambient_occlusion: None,
+            palette: palette.to_vec(),
+            cycled: cycled.to_vec(),
+            tints: HashMap::new()
+        }
+    }
+
+    /// Builds the tint colormap for a light `color_ramp`, lights without one only brighten surfaces
+    pub fn prepare_tint(&mut self, color_ramp: u8) {
+        let Some(&target) = self.palette.get(color_ramp as usize) else { return; };
+        let (palette, cycled) = (&self.palette, &self.cycled);
+        self.tints.entry(color_ramp).or_insert_with(|| Colormap::fade(palette, cycled, target, TINT_LEVELS));
+    }
+
+    #[inline(always)]
+    pub fn is_cycled(&self, color_id: u8) -> bool {
+        self.cycled.contains(&color_id)
+    }
+
+    /// Whether hits on `color_id` are drawn as they are, without lighting or fog
+    #[inline(always)]
+    pub fn is_unshaded(&self, color_id: u8) -> bool {
+        self.materials.get(color_id).emissive || self.is_cycled(color_id)
+    }
+
+    pub fn face_level(&self, normal: Vec3A, lights: &LightSum) -> usize {
+        // rays starting inside a voxel have no face to light, so they are drawn as is
+        if normal == Vec3A::ZERO { return LIGHT_LEVELS - 1; }
+
+        let diffuse = normal.dot(self.to_light).max(0.0);
+        let unlit = (self.ambient + (1.0 - self.ambient) * diffuse) * (1.0 - lights.occlusion);
+        let brightness = unlit + lights.brightness;
+        (brightness.min(1.0) * (LIGHT_LEVELS - 1) as f32).round() as usize
+    }
+
+    /// Final palette index of a hit drawn at screen pixel `(x, y)` by a camera whose far plane is `far` away
+    #[inline(always)]
+    pub fn shade(&self, hit: &RayHit, lights: &LightSum, far: f32, x: usize, y: usize) -> u8 {
+        if self.is_unshaded(hit.color_id) { return hit.color_id; }
+
+        let mut lit = self.light_table.get(hit.color_id, self.face_level(hit.normal, lights));
+        if let Some((color_ramp, contribution)) = lights.tint {
+            if let Some(tint) = self.tints.get(&color_ramp) {
+                let amount = contribution.min(1.0) * MAX_TINT;
+                lit = tint.get(lit, (amount * (TINT_LEVELS - 1) as f32).round() as usize);
+            }
+        }
+        self.fog.apply(lit, hit.t, far, x, y)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use glam::{vec3a, Vec3A};
+
+    use crate::{
+        utils::{bvh::EntityBvh, materials::MaterialTable, rendering::FAR},
+        voxel_model::VoxelModel
+    };
+
+    use super::{AmbientOcclusion, Fog, LightSum, Shading, DITHER};
+
+    #[test]
+    fn test_fog() {
+        let palette: Vec<[u8; 3]> = (0..16).map(|i| [i * 16; 3]).collect();
+        let fog = Fog::new(&palette, &[], 0, 100.0);
+
+        for (x, y) in [(0, 0), (1, 2), (3, 3)] {
+            assert_eq!(fog.apply(15, 50.0, FAR, x, y), 15);
+            assert_eq!(fog.apply(15, FAR, FAR, x, y), 0);
+            assert_eq!(fog.apply(15, f32::INFINITY, FAR, x, y), 0);
+            // cameras with a closer far plane are fully fogged by it
+            assert_eq!(fog.apply(15, 200.0, 200.0, x, y), 0);
+            assert_ne!(fog.apply(15, 200.0, FAR, x, y), 0);
+        }
+
+        // halfway a 4x4 block mixes the neighbouring rows instead of a single color
+        let t = 100.0 + (FAR - 100.0) * 0.5;
+        let mut block: Vec<u8> = (0..16).map(|ix| fog.apply(15, t, FAR, ix % 4, ix / 4)).collect();
+        block.sort();
+        block.dedup();
+        assert_eq!(block, vec![7, 8]);
+        assert!(DITHER.iter().flatten().all(|&d| (0.0..1.0).contains(&d)));
+    }
+
+    #[test]
+    fn test_ambient_occlusion() {
+        // a floor meeting a wall along the +X side
+        let corner = VoxelModel::make_32x32x32(|x, y, _| (y < 8 || x >= 24) as u8);
+        let bvh = EntityBvh::build([(Vec3A::ZERO, &corner)]);
+        let ambient_occlusion = AmbientOcclusion { radius: 8.0, strength: 0.5 };
+
+        assert_eq!(ambient_occlusion.occlusion(&bvh, vec3a(8.0, 8.0, 16.0), Vec3A::Y), 0.0);
+        // only the ray leaning towards the wall gets blocked
+        assert_eq!(ambient_occlusion.occlusion(&bvh, vec3a(22.0, 8.0, 16.0), Vec3A::Y), 0.125);
+        assert_eq!(ambient_occlusion.occlusion(&bvh, vec3a(22.0, 8.0, 16.0), Vec3A::ZERO), 0.0);
+
+        let palette: Vec<[u8; 3]> = (0..16).map(|i| [i * 16; 3]).collect();
+        let shading = Shading::new(&palette, &[], MaterialTable::default(), 0);
+        let open = shading.face_level(Vec3A::Y, &LightSum::default());
+        let occluded = shading.face_level(Vec3A::Y, &LightSum { occlusion: 0.5, ..LightSum::default() });
+        assert!(occluded < open);
+    }
+}
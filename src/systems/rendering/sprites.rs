@@ -0,0 +1,214 @@
+use edict::world::World;
+use glam::Vec3A;
+use retro_blit::{
+    rendering::{blittable::{BufferProvider, BufferProviderMut, SizedSurface}, BlittableSurface},
+    window::RetroBlitContext
+};
+
+use crate::{
+    components::{Position, Sprite},
+    resources::DepthBuffer,
+    systems::{rendering::voxels::{camera_views, RowRays}, BaseSystem},
+    utils::rendering::{FrustumPlane, Viewport}
+};
+
+/// Pixels of the surface sprites are cut from
+pub struct SpriteSheet {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>
+}
+
+impl SpriteSheet {
+    pub fn from_surface(surface: &BlittableSurface) -> Self {
+        Self { width: surface.get_width(), height: surface.get_height(), pixels: surface.get_buffer().to_vec() }
+    }
+
+    /// Palette index at `(x, y)`, transparent outside of the sheet
+    #[inline(always)]
+    fn get(&self, x: usize, y: usize) -> u8 {
+        if x >= self.width || y >= self.height { return 0; }
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// Fractional viewport pixel a world point is seen at, `None` when it is behind the near plane.
+/// Pixels are numbered the way `RowRays` spreads rays, so a point lands where the ray through it is cast.
+fn project([near_plane, far_plane]: &[FrustumPlane; 2], viewport: Viewport, point: Vec3A) -> Option<(f32, f32)> {
+    let near_center = (near_plane.top_left + near_plane.bottom_right) * 0.5;
+    let far_center = (far_plane.top_left + far_plane.bottom_right) * 0.5;
+    let forward = (far_center - near_center).normalize();
+    let s = (point - near_center).dot(forward) / (far_center - near_center).dot(forward);
+    if s < 0.0 { return None; }
+
+    // the slice of the frustum at the depth of the point
+    let top_left = near_plane.top_left.lerp(far_plane.top_left, s);
+    let across = near_plane.top_right.lerp(far_plane.top_right, s) - top_left;
+    let down = near_plane.bottom_left.lerp(far_plane.bottom_left, s) - top_left;
+    let u = (point - top_left).dot(across) / across.length_squared();
+    let v = (point - top_left).dot(down) / down.length_squared();
+    Some((u * (viewport.width.max(2) - 1) as f32, v * (viewport.height.max(2) - 1) as f32))
+}
+
+/// Draws `sprite` placed at `pos` into the `viewport` of `buffer`, a framebuffer `stride` pixels wide
+#[allow(clippy::too_many_arguments)]
+fn draw_sprite(
+    sheet: &SpriteSheet,
+    sprite: &Sprite,
+    pos: Vec3A,
+    planes: &[FrustumPlane; 2],
+    viewport: Viewport,
+    buffer: &mut [u8],
+    depth: &mut [f32],
+    stride: usize
+) {
+    let [near_plane, far_plane] = planes;
+    let right = (near_plane.top_right - near_plane.top_left).normalize();
+    let up = (near_plane.top_left - near_plane.bottom_left).normalize();
+    let forward = ((far_plane.top_left + far_plane.bottom_right) - (near_plane.top_left + near_plane.bottom_right)).normalize();
+
+    let top_left = pos - right * sprite.anchor.x * sprite.size.x + up * sprite.anchor.y * sprite.size.y;
+    let corners = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]
+        .map(|(x, y)| top_left + right * x * sprite.size.x - up * y * sprite.size.y);
+
+    // pixels the sprite may cover, the whole viewport when it reaches behind the near plane
+    let (mut i_range, mut j_range) = (0..viewport.width, 0..viewport.height);
+    if let Some(projected) = corners.iter().map(|&corner| project(planes, viewport, corner)).collect::<Option<Vec<_>>>() {
+        let (min, max) = projected.iter().fold(
+            ((f32::INFINITY, f32::INFINITY), (f32::NEG_INFINITY, f32::NEG_INFINITY)),
+            |(min, max), &(u, v)| ((min.0.min(u), min.1.min(v)), (max.0.max(u), max.1.max(v)))
+        );
+        let clamp = |value: f32, len: usize| value.max(0.0).min(len as f32) as usize;
+        i_range = clamp(min.0.floor(), viewport.width)..clamp(max.0.ceil() + 1.0, viewport.width);
+        j_range = clamp(min.1.floor(), viewport.height)..clamp(max.1.ceil() + 1.0, viewport.height);
+    }
+
+    let region = sprite.region;
+    for j in j_range {
+        let row = RowRays::new(planes, j, viewport.height);
+        for i in i_range.clone() {
+            let (ray_origin, ray_dir) = row.ray(i, viewport.width);
+            let facing = ray_dir.dot(forward);
+            if facing <= 0.0 { continue; }
+            let t = (pos - ray_origin).dot(forward) / facing;
+            if t < 0.0 { continue; }
+
+            let ix = (viewport.y + j) * stride + viewport.x + i;
+            if t >= depth[ix] { continue; }
+
+            let offset = ray_origin + ray_dir * t - top_left;
+            let (x, y) = (offset.dot(right) / sprite.size.x, -offset.dot(up) / sprite.size.y);
+            if !(0.0..1.0).contains(&x) || !(0.0..1.0).contains(&y) { continue; }
+
+            let color_id = sheet.get(
+                region.x + (x * region.width as f32) as usize,
+                region.y + (y * region.height as f32) as usize
+            );
+            // index 0 is transparent, as it is for voxels
+            if color_id == 0 { continue; }
+            buffer[ix] = color_id;
+            depth[ix] = t;
+        }
+    }
+}
+
+/// Draws the sprites of `world` as billboards facing every active camera on top of `buffer`, a
+/// framebuffer `width` pixels wide. Pixels are only drawn where they are closer than what `depth`
+/// holds, which they update, so sprites hide behind voxels and behind each other.
+pub fn render_sprites(world: &World, sheet: &SpriteSheet, buffer: &mut [u8], width: usize, depth: &mut DepthBuffer) {
+    assert_eq!(depth.width, width);
+    assert_eq!(depth.data.len(), buffer.len());
+    let height = buffer.len() / width.max(1);
+
+    let sprites: Vec<_> = world.view::<(&Sprite, &Position)>()
+        .into_iter()
+        .map(|(sprite, pos)| (*sprite, pos.value))
+        .collect();
+    if sprites.is_empty() { return; }
+
+    for (camera, planes) in camera_views(world, width, height) {
+        for (sprite, pos) in sprites.iter() {
+            draw_sprite(sheet, sprite, *pos, &planes, camera.viewport, buffer, &mut depth.data, width);
+        }
+    }
+}
+
+/// Draws sprites after the voxel pass, testing them against the `DepthBuffer` resource it leaves behind
+pub struct SpriteRenderingSystem {
+    pub sheet: SpriteSheet
+}
+
+impl SpriteRenderingSystem {
+    pub fn new(sheet: SpriteSheet) -> Self {
+        Self { sheet }
+    }
+}
+
+impl BaseSystem for SpriteRenderingSystem {
+    fn run(&mut self, ctx: &mut RetroBlitContext, world: &mut World, _dt: f32) {
+        let (sw, sh) = (ctx.get_width(), ctx.get_height());
+
+        let mut depth = match world.remove_resource::<DepthBuffer>() {
+            Some(depth) if depth.width == sw && depth.height == sh => depth,
+            _ => DepthBuffer::new(sw, sh)
+        };
+        render_sprites(world, &self.sheet, ctx.get_buffer_mut(), sw, &mut depth);
+        world.insert_resource(depth);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edict::world::World;
+    use glam::{vec2, vec3a};
+
+    use crate::{
+        components::{Camera, Position, Sprite, SurfaceRegion, ViewAngle},
+        resources::DepthBuffer,
+        utils::rendering::Viewport
+    };
+
+    use super::{render_sprites, SpriteSheet};
+
+    const BACKGROUND: u8 = 255;
+
+    #[test]
+    fn test_sprites() {
+        // the left half of the sprite is solid, the right half transparent
+        let sheet = SpriteSheet {
+            width: 8,
+            height: 8,
+            pixels: (0..64).map(|ix| if ix % 8 < 4 { 3 } else { 0 }).collect()
+        };
+        let mut world = World::new();
+        world.spawn((Position { value: vec3a(0.0, 0.0, 0.0) }, ViewAngle { value: 0.0 }, Camera::new(Viewport::full_screen(64, 64))));
+        let region = SurfaceRegion { x: 0, y: 0, width: 8, height: 8 };
+        world.spawn((Position { value: vec3a(0.0, 0.0, 64.0) }, Sprite { region, anchor: vec2(0.5, 0.5), size: vec2(32.0, 32.0) }));
+
+        let mut buffer = vec![BACKGROUND; 64 * 64];
+        let mut depth = DepthBuffer::new(64, 64);
+        render_sprites(&world, &sheet, &mut buffer, 64, &mut depth);
+
+        let drawn = buffer.iter().filter(|&&clr| clr == 3).count();
+        // 32 voxels across at 64 away cover about a seventh of the view
+        assert!(drawn > 64, "only {} pixels drawn", drawn);
+        for (ix, (&clr, &t)) in buffer.iter().zip(depth.data.iter()).enumerate() {
+            assert!(clr == 3 || clr == BACKGROUND);
+            assert_eq!(clr == 3, t.is_finite());
+            // the anchor sits in the middle of the view, with the solid half to the left of it
+            if clr == 3 {
+                assert!(ix % 64 < 32, "drawn at ({}, {})", ix % 64, ix / 64);
+                assert!(t > 60.0 && t < 68.0);
+            }
+        }
+        assert_eq!(buffer[32 * 64 + 30], 3);
+
+        // voxels in front of the sprite hide it
+        let mut buffer = vec![BACKGROUND; 64 * 64];
+        let mut depth = DepthBuffer::new(64, 64);
+        depth.data[32 * 64..].fill(16.0);
+        render_sprites(&world, &sheet, &mut buffer, 64, &mut depth);
+        assert!(buffer[..32 * 64].contains(&3));
+        assert!(buffer[32 * 64..].iter().all(|&clr| clr == BACKGROUND));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    components::Subsampling,
+    utils::rendering::{FrustumPlane, Viewport}
+};
+
+/// Depth differences up to this fraction of the nearer depth still count as the same surface
+const DEPTH_TOLERANCE: f32 = 0.05;
+
+/// Color and depth of a traced pixel, `None` when its ray hit nothing
+pub type Sample = Option<(u8, f32)>;
+
+/// Frames it takes the pattern of traced pixels to shift over every pixel of a viewport once
+pub fn phase_count(subsampling: Subsampling) -> usize {
+    match subsampling {
+        Subsampling::Off => 1,
+        Subsampling::Checkerboard => 2,
+        Subsampling::Grid2x2 => 4
+    }
+}
+
+/// How far the pattern of traced pixels is shifted in frame `phase`
+#[inline(always)]
+fn shift(subsampling: Subsampling, phase: usize) -> (usize, usize) {
+    match subsampling {
+        Subsampling::Off => (0, 0),
+        Subsampling::Checkerboard => (phase % 2, 0),
+        Subsampling::Grid2x2 => [(0, 0), (1, 1), (1, 0), (0, 1)][phase % 4]
+    }
+}
+
+/// Whether pixel `(i, j)` of a viewport gets traced in frame `phase` rather than filled otherwise
+#[inline(always)]
+pub fn is_traced(subsampling: Subsampling, phase: usize, i: usize, j: usize) -> bool {
+    let (di, dj) = shift(subsampling, phase);
+    let (i, j) = (i + di, j + dj);
+    match subsampling {
+        Subsampling::Off => true,
+        Subsampling::Checkerboard => (i + j).is_multiple_of(2),
+        Subsampling::Grid2x2 => i.is_multiple_of(2) && j.is_multiple_of(2)
+    }
+}
+
+/// Offsets of the traced pixels a pixel that is not traced itself in frame `phase` gets filled from
+pub fn neighbours(subsampling: Subsampling, phase: usize, i: usize, j: usize) -> &'static [(isize, isize)] {
+    const CROSS: [(isize, isize); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+    const DIAGONALS: [(isize, isize); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+    if is_traced(subsampling, phase, i, j) { return &[]; }
+    let (di, dj) = shift(subsampling, phase);
+    match (subsampling, (i + di) % 2, (j + dj) % 2) {
+        (Subsampling::Checkerboard, ..) => &CROSS,
+        (Subsampling::Grid2x2, 1, 0) => &CROSS[..2],
+        (Subsampling::Grid2x2, 0, 1) => &CROSS[2..],
+        (Subsampling::Grid2x2, ..) => &DIAGONALS,
+        (Subsampling::Off, ..) => &[]
+    }
+}
+
+/// Fills a pixel from the samples of its neighbours, `None` standing for a neighbour outside of
+/// the viewport. Neighbours which all missed or all show the same color at about the same depth
+/// are taken to lie on one surface. Anything else is an edge, and the pixel has to be traced,
+/// which is signalled by returning `None`.
+pub fn interpolate(neighbours: impl IntoIterator<Item = Option<Sample>>) -> Option<Sample> {
+    let mut count = 0;
+    let mut first: Option<Sample> = None;
+    let (mut min_t, mut max_t, mut sum_t) = (f32::INFINITY, 0.0f32, 0.0);
+
+    for sample in neighbours {
+        let sample = sample?;
+        count += 1;
+        match (first.get_or_insert(sample), sample) {
+            (None, None) => (),
+            (Some((color, _)), Some((neighbour_color, t))) if *color == neighbour_color => {
+                min_t = min_t.min(t);
+                max_t = max_t.max(t);
+                sum_t += t;
+            },
+            _ => return None
+        }
+    }
+
+    match first? {
+        None => Some(None),
+        Some(_) if max_t - min_t > min_t * DEPTH_TOLERANCE => None,
+        Some((color, _)) => Some(Some((color, sum_t / count as f32)))
+    }
+}
+
+/// Samples a subsampled view traced in its last frames. Frames reuse them for the pixels they do
+/// not trace themselves as long as the view has not moved in between, and shift the pattern of
+/// traced pixels, so a view which stays put is traced all over after [`phase_count`] frames.
+///
+/// A pixel is traced again every [`phase_count`] frames, so entities moving in front of a view
+/// which stays put may show up a few frames late on some of its pixels.
+#[derive(Default)]
+pub struct SampleHistory {
+    view: Option<([FrustumPlane; 2], Viewport, Subsampling)>,
+    phase: usize,
+    /// latest sample traced for every pixel, `None` for pixels not traced since the view moved
+    samples: Vec<Option<Sample>>
+}
+
+impl SampleHistory {
+    /// Starts a frame of `viewport` seen through `planes`, returning the phase of the pattern of
+    /// traced pixels along with the samples of the last frames, which are all `None` when the view moved
+    pub fn next_frame(
+        &mut self,
+        planes: &[FrustumPlane; 2],
+        viewport: Viewport,
+        subsampling: Subsampling
+    ) -> (usize, Vec<Option<Sample>>) {
+        let view = Some((*planes, viewport, subsampling));
+        if self.view == view && self.samples.len() == viewport.width * viewport.height {
+            self.phase = (self.phase + 1) % phase_count(subsampling);
+            return (self.phase, std::mem::take(&mut self.samples));
+        }
+        self.view = view;
+        self.phase = 0;
+        (0, vec![None; viewport.width * viewport.height])
+    }
+
+    /// Keeps the latest `samples` of the frame started last for the next one
+    pub fn store(&mut self, samples: Vec<Option<Sample>>) {
+        self.samples = samples;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::components::Subsampling;
+
+    use super::{interpolate, is_traced, neighbours, phase_count};
+
+    #[test]
+    fn test_subsampling_patterns() {
+        for subsampling in [Subsampling::Off, Subsampling::Checkerboard, Subsampling::Grid2x2] {
+            for phase in 0..phase_count(subsampling) {
+                for j in 0..4 {
+                    for i in 0..4 {
+                        let offsets = neighbours(subsampling, phase, i, j);
+                        // traced pixels need no neighbours, and the others only rely on traced ones
+                        assert_eq!(offsets.is_empty(), is_traced(subsampling, phase, i, j));
+                        for (di, dj) in offsets {
+                            let (ni, nj) = ((i as isize + 2 + di) as usize, (j as isize + 2 + dj) as usize);
+                            assert!(is_traced(subsampling, phase, ni, nj), "{:?} {} at ({}, {})", subsampling, phase, i, j);
+                        }
+                    }
+                }
+            }
+            // over all of its phases, the pattern traces every pixel once
+            for ix in 0..16 {
+                let traced = (0..phase_count(subsampling))
+                    .filter(|&phase| is_traced(subsampling, phase, ix % 4, ix / 4))
+                    .count();
+                assert_eq!(traced, 1, "{:?} at {}", subsampling, ix);
+            }
+        }
+        assert_eq!((0..16).filter(|&ix| is_traced(Subsampling::Grid2x2, 0, ix % 4, ix / 4)).count(), 4);
+
+        assert_eq!(interpolate([Some(Some((3, 10.0))), Some(Some((3, 10.4)))]), Some(Some((3, 10.2))));
+        assert_eq!(interpolate([Some(None), Some(None)]), Some(None));
+        // edges in color, in depth, along the silhouette and at the border of the viewport
+        assert_eq!(interpolate([Some(Some((3, 10.0))), Some(Some((4, 10.0)))]), None);
+        assert_eq!(interpolate([Some(Some((3, 10.0))), Some(Some((3, 20.0)))]), None);
+        assert_eq!(interpolate([Some(Some((3, 10.0))), Some(None)]), None);
+        assert_eq!(interpolate([Some(None), None]), None);
+    }
+}
@@ -1,111 +1,888 @@
+use std::collections::HashMap;
+
 use edict::world::World;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use glam::{Vec3A, Vec4};
+use rayon::{iter::{IndexedParallelIterator, ParallelIterator}, slice::{ParallelSlice, ParallelSliceMut}};
 use retro_blit::{
-    rendering::{blittable::{BufferProviderMut, SizedSurface}, fonts::{font_align::{HorizontalAlignment, VerticalAlignment}, tri_spaced::{Font, TextDrawer}}},
-    utility::StopWatch,
+    rendering::blittable::{BufferProviderMut, SizedSurface},
     window::RetroBlitContext
 };
 use crate::{
-    components::{PlayerTag, Position, ViewAngle, Voxel},
-    systems::BaseSystem,
-    utils::{ray_queries::VoxelIntersector, rendering::gen_frustum_planes}
+    components::{Camera, PointLight, Position, Subsampling, ViewAngle, ViewTilt},
+    resources::DepthBuffer,
+    systems::{
+        rendering::{
+            shading::{reflected_ray, LightInstance, LightSum, Shading},
+            subsampling::{interpolate, is_traced, neighbours, Sample, SampleHistory}
+        },
+        BaseSystem
+    },
+    utils::{
+        bvh::EntityBvh,
+        materials::MaterialTable,
+        ray_packets::{RayPacket4, PACKET_WIDTH},
+        ray_queries::RayHit,
+        rendering::{FrustumPlane, Viewport}
+    }
 };
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TracingMode {
+    /// One ray per pixel, kept as a reference and fallback
+    Scalar,
+    /// 2x2 pixel quads traced together as a four-ray packet
+    Packet2x2
+}
+
+/// Draws the active cameras of a world into a plain framebuffer, so frames can be rendered
+/// without a window, e.g. in tests or CI
+pub struct VoxelRenderer {
+    pub tracing_mode: TracingMode,
+    pub shading: Shading,
+    /// what the subsampled cameras traced in their last frame, by viewport
+    histories: HashMap<Viewport, SampleHistory>
+}
+
+/// Thin adapter running a [`VoxelRenderer`] on the window framebuffer and keeping its depth in
+/// the `DepthBuffer` resource
 pub struct VoxelRenderingSystem {
-    font: Font
+    pub renderer: VoxelRenderer
 }
 
 impl VoxelRenderingSystem {
-    pub fn new() -> Self {
+    pub fn new(palette: &[[u8; 3]], cycled: &[u8], materials: MaterialTable, fog_color_id: u8) -> Self {
+        Self { renderer: VoxelRenderer::new(palette, cycled, materials, fog_color_id) }
+    }
+}
+
+/// What a frame is traced against: the entities, the lights and the way their hits are shaded
+#[derive(Clone, Copy)]
+pub struct RenderScene<'a> {
+    pub bvh: &'a EntityBvh<'a>,
+    pub shading: &'a Shading,
+    pub lights: &'a [LightInstance],
+    /// distance at which hits are fully fogged, the far plane of the camera being rendered
+    pub far: f32
+}
+
+impl<'a> RenderScene<'a> {
+    /// Shades a single hit, `travelled` being the distance covered by the ray before it started,
+    /// so reflections get fogged by the full length of their path
+    #[inline(always)]
+    fn shade_hit(&self, (ray_origin, ray_dir): (Vec3A, Vec3A), hit: &RayHit, travelled: f32, x: usize, y: usize) -> u8 {
+        // unshaded voxels ignore light, so there is no need to gather it
+        if self.shading.is_unshaded(hit.color_id) { return hit.color_id; }
+
+        let point = ray_origin + ray_dir * hit.t;
+        let mut lights = if self.lights.is_empty() {
+            LightSum::default()
+        } else {
+            LightSum::gather(self.bvh, self.lights, point, hit.normal)
+        };
+        if let Some(ambient_occlusion) = self.shading.ambient_occlusion {
+            lights.occlusion = ambient_occlusion.occlusion(self.bvh, point, hit.normal);
+        }
+        self.shading.shade(&RayHit { t: travelled + hit.t, ..*hit }, &lights, self.far, x, y)
+    }
+
+    /// Palette index for the closest `hit` of a ray drawn over `background`. Translucent hits are
+    /// blended over the first opaque voxel behind them, or over `background` when there is none.
+    /// Reflective hits mix in what the mirrored ray shows, up to `Shading::max_bounces` deep.
+    /// Hits on cycled entries do neither, as blending would take them off the cycle.
+    #[inline(always)]
+    fn shade(&self, ray: (Vec3A, Vec3A), hit: &RayHit, x: usize, y: usize, background: u8) -> u8 {
+        self.shade_bounce(ray, hit, 0.0, 0, x, y, background)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn shade_bounce(
+        &self,
+        ray: (Vec3A, Vec3A),
+        hit: &RayHit,
+        travelled: f32,
+        bounce: usize,
+        x: usize,
+        y: usize,
+        background: u8
+    ) -> u8 {
+        let materials = &self.shading.materials;
+        let material = materials.get(hit.color_id);
+        let mut color = self.shade_hit(ray, hit, travelled, x, y);
+        if self.shading.is_cycled(hit.color_id) { return color; }
+
+        if material.translucent {
+            let back = match self.bvh.cast_ray_through(ray.0, ray.1, Some(materials)) {
+                Some(behind) => self.shade_hit(ray, &behind, travelled, x, y),
+                None => background
+            };
+            color = self.shading.blend_table.get(color, back);
+        }
+
+        if material.reflective && bounce < self.shading.max_bounces {
+            if let Some(reflected) = reflected_ray(ray, hit) {
+                // point lights are culled to the part of the view a band covers, while reflected
+                // rays may end up anywhere, so they are lit by the directional light alone
+                let unlit = RenderScene { lights: &[], ..*self };
+                let mirrored = match self.bvh.cast_ray(reflected.0, reflected.1) {
+                    Some(mirrored) => unlit.shade_bounce(reflected, &mirrored, travelled + hit.t, bounce + 1, x, y, background),
+                    None => background
+                };
+                color = self.shading.blend_table.get(color, mirrored);
+            }
+        }
+        color
+    }
+}
+
+/// Rays of a viewport row, shared with passes drawn on top of the voxels so they line up with them
+pub(super) struct RowRays {
+    near_left: Vec3A,
+    near_right: Vec3A,
+    far_left: Vec3A,
+    far_right: Vec3A
+}
+
+impl RowRays {
+    pub(super) fn new([near_plane, far_plane]: &[FrustumPlane; 2], j: usize, height: usize) -> Self {
+        let v = j as f32 / (height.max(2) - 1) as f32;
+        Self {
+            near_left: near_plane.top_left.lerp(near_plane.bottom_left, v),
+            near_right: near_plane.top_right.lerp(near_plane.bottom_right, v),
+            far_left: far_plane.top_left.lerp(far_plane.bottom_left, v),
+            far_right: far_plane.top_right.lerp(far_plane.bottom_right, v)
+        }
+    }
+
+    #[inline(always)]
+    pub(super) fn ray(&self, i: usize, width: usize) -> (Vec3A, Vec3A) {
+        let u = i as f32 / (width.max(2) - 1) as f32;
+        let ray_origin = self.near_left.lerp(self.near_right, u);
+        let far = self.far_left.lerp(self.far_right, u);
+        (ray_origin, (far - ray_origin).normalize())
+    }
+}
+
+/// Lights whose sphere of influence reaches into the slice of the view frustum covered by rows `j_range`
+fn band_lights(
+    lights: &[LightInstance],
+    planes: &[FrustumPlane; 2],
+    viewport: Viewport,
+    j_range: std::ops::Range<usize>
+) -> Vec<LightInstance> {
+    let top = RowRays::new(planes, j_range.start, viewport.height);
+    let bottom = RowRays::new(planes, j_range.end - 1, viewport.height);
+    let corners = [
+        top.near_left, top.near_right, top.far_left, top.far_right,
+        bottom.near_left, bottom.near_right, bottom.far_left, bottom.far_right
+    ];
+    let inside = corners.iter().sum::<Vec3A>() / corners.len() as f32;
+
+    // side planes of the slice, with normals pointing inwards
+    let sides = [
+        (top.near_left, top.near_right, top.far_left),
+        (bottom.near_left, bottom.near_right, bottom.far_left),
+        (top.near_left, bottom.near_left, top.far_left),
+        (top.near_right, bottom.near_right, top.far_right)
+    ].map(|(a, b, c)| {
+        let normal = (b - a).cross(c - a).normalize_or_zero();
+        if normal.dot(inside - a) < 0.0 { (-normal, a) } else { (normal, a) }
+    });
+
+    lights
+        .iter()
+        .filter(|instance| sides.iter().all(|&(normal, a)| normal.dot(instance.pos - a) > -instance.light.radius))
+        .copied()
+        .collect()
+}
+
+/// Traces viewport rows `j_range` into `rows`, which holds those rows of the framebuffer
+fn trace_rows_scalar(
+    scene: RenderScene,
+    planes: &[FrustumPlane; 2],
+    viewport: Viewport,
+    j_range: std::ops::Range<usize>,
+    rows: &mut [u8],
+    depth_rows: &mut [f32],
+    stride: usize
+) {
+    for (row_ix, j) in j_range.enumerate() {
+        let row = RowRays::new(planes, j, viewport.height);
+
+        let row_offset = row_ix * stride + viewport.x;
+        let slice_mut = &mut rows[row_offset..][..viewport.width];
+        let depth_mut = &mut depth_rows[row_offset..][..viewport.width];
+
+        for (i, (clr, depth)) in slice_mut.iter_mut().zip(depth_mut.iter_mut()).enumerate() {
+            let (ray_origin, ray_dir) = row.ray(i, viewport.width);
+
+            let Some(hit) = scene.bvh.cast_ray(ray_origin, ray_dir) else { continue; };
+            *clr = scene.shade((ray_origin, ray_dir), &hit, viewport.x + i, viewport.y + j, *clr);
+            *depth = hit.t;
+        }
+    }
+}
+
+fn trace_rows_packets(
+    scene: RenderScene,
+    planes: &[FrustumPlane; 2],
+    viewport: Viewport,
+    j_range: std::ops::Range<usize>,
+    rows: &mut [u8],
+    depth_rows: &mut [f32],
+    stride: usize
+) {
+    let width = viewport.width;
+    for j in j_range.clone().step_by(2) {
+        let row_rays = [j, (j + 1).min(j_range.end - 1)].map(|j| RowRays::new(planes, j, viewport.height));
+        let row_offset = (j - j_range.start) * stride + viewport.x;
+
+        for i in (0..width).step_by(2) {
+            // lanes are laid out as top left, top right, bottom left, bottom right
+            let pixels: [Option<usize>; PACKET_WIDTH] = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(di, dj)| {
+                (i + di < width && j + dj < j_range.end).then(|| row_offset + dj * stride + i + di)
+            });
+            let rays = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(di, dj)| row_rays[dj].ray((i + di).min(width - 1), width));
+
+            let packet = RayPacket4::from_rays(rays);
+            let mut min_t = Vec4::from(pixels.map(|p| if p.is_some() { f32::INFINITY } else { f32::NEG_INFINITY }));
+            let (mut color_ids, mut normals) = ([0; PACKET_WIDTH], [Vec3A::ZERO; PACKET_WIDTH]);
+            scene.bvh.cast_ray_packet(&packet, &mut min_t, &mut color_ids, &mut normals);
+
+            for (lane, (pixel, (di, dj))) in pixels.into_iter().zip([(0, 0), (1, 0), (0, 1), (1, 1)]).enumerate() {
+                let Some(ix) = pixel else { continue; };
+                if color_ids[lane] == 0 { continue; }
+                let hit = RayHit { t: min_t[lane], color_id: color_ids[lane], normal: normals[lane] };
+                rows[ix] = scene.shade(rays[lane], &hit, viewport.x + i + di, viewport.y + j + dj, rows[ix]);
+                depth_rows[ix] = hit.t;
+            }
+        }
+    }
+}
+
+/// Traces arbitrary `pixels` of the viewport, four at a time as packets in [`TracingMode::Packet2x2`].
+/// Translucent hits with nothing behind them are blended over `background(i, j)`.
+fn trace_pixels(
+    scene: RenderScene,
+    planes: &[FrustumPlane; 2],
+    viewport: Viewport,
+    tracing_mode: TracingMode,
+    pixels: &[(usize, usize)],
+    background: impl Fn(usize, usize) -> u8
+) -> Vec<Sample> {
+    let ray = |(i, j): (usize, usize)| RowRays::new(planes, j, viewport.height).ray(i, viewport.width);
+    let shade = |ray, hit: Option<RayHit>, (i, j): (usize, usize)| {
+        hit.map(|hit| (scene.shade(ray, &hit, viewport.x + i, viewport.y + j, background(i, j)), hit.t))
+    };
+
+    match tracing_mode {
+        TracingMode::Scalar => pixels
+            .iter()
+            .map(|&pixel| {
+                let (ray_origin, ray_dir) = ray(pixel);
+                shade((ray_origin, ray_dir), scene.bvh.cast_ray(ray_origin, ray_dir), pixel)
+            })
+            .collect(),
+        TracingMode::Packet2x2 => pixels
+            .chunks(PACKET_WIDTH)
+            .flat_map(|chunk| {
+                // lanes past the end of the chunk repeat its first ray and are masked out
+                let rays = [0, 1, 2, 3].map(|lane| ray(*chunk.get(lane).unwrap_or(&chunk[0])));
+                let mut min_t = Vec4::from([0, 1, 2, 3].map(|lane| {
+                    if lane < chunk.len() { f32::INFINITY } else { f32::NEG_INFINITY }
+                }));
+                let (mut color_ids, mut normals) = ([0; PACKET_WIDTH], [Vec3A::ZERO; PACKET_WIDTH]);
+                scene.bvh.cast_ray_packet(&RayPacket4::from_rays(rays), &mut min_t, &mut color_ids, &mut normals);
+
+                chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(lane, &pixel)| {
+                        let hit = (color_ids[lane] != 0)
+                            .then(|| RayHit { t: min_t[lane], color_id: color_ids[lane], normal: normals[lane] });
+                        shade(rays[lane], hit, pixel)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+/// Lights of every band of `band_rows` rows the viewport is split into, see [`band_lights`]
+fn lights_per_band(
+    lights: &[LightInstance],
+    planes: &[FrustumPlane; 2],
+    viewport: Viewport,
+    band_rows: usize
+) -> Vec<Vec<LightInstance>> {
+    (0..viewport.height)
+        .step_by(band_rows)
+        .map(|j_start| band_lights(lights, planes, viewport, j_start..(j_start + band_rows).min(viewport.height)))
+        .collect()
+}
+
+/// Traces the pixels of the viewport picked by `subsampling` band by band in parallel, then fills
+/// in the rest in a second parallel pass, from the earlier frames of `history` where the view stayed
+/// put and else from their neighbours, tracing those whose neighbours disagree as well. The second
+/// pass reads the samples of the whole viewport, so no pixel is traced twice.
+///
+/// `viewport_rows` and `viewport_depth_rows` hold the framebuffer rows the viewport covers.
+#[allow(clippy::too_many_arguments)]
+fn render_subsampled(
+    scene: RenderScene,
+    planes: &[FrustumPlane; 2],
+    viewport_rows: &mut [u8],
+    viewport_depth_rows: &mut [f32],
+    stride: usize,
+    viewport: Viewport,
+    band_rows: usize,
+    tracing_mode: TracingMode,
+    subsampling: Subsampling,
+    history: &mut SampleHistory
+) {
+    let width = viewport.width;
+    let lights = lights_per_band(scene.lights, planes, viewport, band_rows);
+    let (phase, previous) = history.next_frame(planes, viewport, subsampling);
+
+    // `None` for pixels which are not traced
+    let mut grid: Vec<Option<Sample>> = vec![None; width * viewport.height];
+    grid.par_chunks_mut(band_rows * width)
+        .zip(viewport_rows.par_chunks(band_rows * stride))
+        .enumerate()
+        .for_each(|(band, (grid_rows, rows))| {
+            let j_start = band * band_rows;
+            let traced: Vec<(usize, usize)> = (j_start..j_start + grid_rows.len() / width)
+                .flat_map(|j| (0..width).map(move |i| (i, j)))
+                .filter(|&(i, j)| is_traced(subsampling, phase, i, j))
+                .collect();
+            let scene = RenderScene { lights: &lights[band], ..scene };
+            let background = |i: usize, j: usize| rows[(j - j_start) * stride + viewport.x + i];
+            for (&(i, j), sample) in traced.iter().zip(trace_pixels(scene, planes, viewport, tracing_mode, &traced, background)) {
+                grid_rows[(j - j_start) * width + i] = Some(sample);
+            }
+        });
+
+    // edges traced in the second pass, kept along with the rest for the next frame
+    let traced_edges: Vec<_> = {
+        let (grid, previous) = (&grid, &previous);
+        viewport_rows
+            .par_chunks_mut(band_rows * stride)
+            .zip(viewport_depth_rows.par_chunks_mut(band_rows * stride))
+            .enumerate()
+            .map(|(band, (rows, depth_rows))| {
+                let j_start = band * band_rows;
+                let offset = |i: usize, j: usize| (j - j_start) * stride + viewport.x + i;
+
+                let mut samples = Vec::with_capacity(rows.len() / stride * width);
+                let mut edges = Vec::new();
+                for j in j_start..j_start + rows.len() / stride {
+                    for i in 0..width {
+                        if let Some(sample) = grid[j * width + i].or(previous[j * width + i]) {
+                            samples.push((i, j, sample));
+                            continue;
+                        }
+                        let filled = interpolate(neighbours(subsampling, phase, i, j).iter().map(|&(di, dj)| {
+                            let (ni, nj) = (i as isize + di, j as isize + dj);
+                            let inside = (0..width as isize).contains(&ni) && (0..viewport.height as isize).contains(&nj);
+                            if inside { grid[nj as usize * width + ni as usize] } else { None }
+                        }));
+                        match filled {
+                            Some(sample) => samples.push((i, j, sample)),
+                            None => edges.push((i, j))
+                        }
+                    }
+                }
+                let scene = RenderScene { lights: &lights[band], ..scene };
+                let traced = trace_pixels(scene, planes, viewport, tracing_mode, &edges, |i, j| rows[offset(i, j)]);
+                let traced: Vec<_> = edges.into_iter().zip(traced).map(|((i, j), sample)| (i, j, sample)).collect();
+
+                for &(i, j, sample) in samples.iter().chain(traced.iter()) {
+                    let Some((clr, t)) = sample else { continue; };
+                    rows[offset(i, j)] = clr;
+                    depth_rows[offset(i, j)] = t;
+                }
+                traced
+            })
+            .flatten()
+            .collect()
+    };
+
+    for (i, j, sample) in traced_edges {
+        grid[j * width + i] = Some(sample);
+    }
+    for (sample, previous) in grid.iter_mut().zip(previous) {
+        *sample = sample.or(previous);
+    }
+    history.store(grid);
+}
+
+/// Traces the voxel scene into the `viewport` rectangle of `buffer`, a framebuffer `stride` pixels wide,
+/// writing the depth of every hit into the matching pixel of `depth`.
+///
+/// Rows are split into bands which rayon traces in parallel. There are a few more bands than
+/// threads so that threads which finish early can pick up remaining work, and bands have an even
+/// number of rows so that 2x2 packets never straddle two of them. Each band only gathers the
+/// lights reaching into its slice of the frustum, which keeps lighting cost bounded.
+///
+/// With `subsampling` the traced pixels of all bands are gathered before any pixel gets filled,
+/// so the result does not depend on where bands are split. `history` holds the last frame of the
+/// view, see [`SampleHistory`], and `None` renders the frame on its own.
+#[allow(clippy::too_many_arguments)]
+pub fn render_voxels(
+    scene: RenderScene,
+    planes: &[FrustumPlane; 2],
+    buffer: &mut [u8],
+    depth: &mut [f32],
+    stride: usize,
+    viewport: Viewport,
+    tracing_mode: TracingMode,
+    subsampling: Subsampling,
+    history: Option<&mut SampleHistory>
+) {
+    assert!(
+        viewport.fits_into(stride, buffer.len() / stride),
+        "viewport {:?} does not fit into a framebuffer {} pixels wide", viewport, stride
+    );
+    assert_eq!(buffer.len(), depth.len());
+    if viewport.width == 0 || viewport.height == 0 { return; }
+
+    let band_count = (rayon::current_num_threads() * 4).min(viewport.height);
+    let band_rows = viewport.height.div_ceil(band_count);
+    let band_rows = band_rows + band_rows % 2;
+
+    let viewport_range = viewport.y * stride..(viewport.y + viewport.height) * stride;
+    let viewport_rows = &mut buffer[viewport_range.clone()];
+    let viewport_depth_rows = &mut depth[viewport_range];
+
+    if subsampling != Subsampling::Off {
+        let mut fresh = SampleHistory::default();
+        let history = history.unwrap_or(&mut fresh);
+        render_subsampled(
+            scene, planes, viewport_rows, viewport_depth_rows, stride, viewport, band_rows, tracing_mode, subsampling, history
+        );
+        return;
+    }
+
+    viewport_rows
+        .par_chunks_mut(band_rows * stride)
+        .zip(viewport_depth_rows.par_chunks_mut(band_rows * stride))
+        .enumerate()
+        .for_each(|(band, (rows, depth_rows))| {
+            let j_start = band * band_rows;
+            let j_range = j_start..j_start + rows.len() / stride;
+            let lights = band_lights(scene.lights, planes, viewport, j_range.clone());
+            let scene = RenderScene { lights: &lights, ..scene };
+            match tracing_mode {
+                TracingMode::Scalar => trace_rows_scalar(scene, planes, viewport, j_range, rows, depth_rows, stride),
+                TracingMode::Packet2x2 => trace_rows_packets(scene, planes, viewport, j_range, rows, depth_rows, stride)
+            }
+        });
+}
+
+/// Active cameras whose viewport fits into a `width` x `height` framebuffer, along with their frustum planes
+pub(super) fn camera_views(world: &World, width: usize, height: usize) -> Vec<(Camera, [FrustumPlane; 2])> {
+    world.view::<(&Camera, &Position, &ViewAngle, Option<&ViewTilt>)>()
+        .into_iter()
+        .filter(|(camera, ..)| camera.active && camera.viewport.fits_into(width, height))
+        .map(|(camera, pos, angle, tilt)| (*camera, camera.frustum_planes(pos.value, angle.value, tilt.copied().unwrap_or_default())))
+        .collect()
+}
+
+impl VoxelRenderer {
+    /// See [`Shading::new`] for the arguments
+    pub fn new(palette: &[[u8; 3]], cycled: &[u8], materials: MaterialTable, fog_color_id: u8) -> Self {
         Self {
-            font: Font::default_font_small().unwrap()
+            tracing_mode: TracingMode::Packet2x2,
+            shading: Shading::new(palette, cycled, materials, fog_color_id),
+            histories: HashMap::new()
+        }
+    }
+
+    /// Renders every active camera into `buffer`, a framebuffer `width` pixels wide, writing the
+    /// depth of every hit into `depth`. Pixels without a hit are left untouched, so the buffer is
+    /// expected to be cleared beforehand. Cameras whose viewport does not fit are skipped.
+    pub fn render(&mut self, world: &World, buffer: &mut [u8], width: usize, depth: &mut DepthBuffer) {
+        assert_eq!(depth.width, width);
+        assert_eq!(depth.data.len(), buffer.len());
+        let height = buffer.len() / width.max(1);
+
+        let cameras = camera_views(world, width, height);
+        if cameras.is_empty() { return; }
+
+        let lights: Vec<_> = world.view::<(&PointLight, &Position)>()
+            .into_iter()
+            .map(|(light, pos)| LightInstance { pos: pos.value, light: *light })
+            .collect();
+        for instance in lights.iter() {
+            self.shading.prepare_tint(instance.light.color_ramp);
+        }
+
+        self.histories.retain(|viewport, _| cameras.iter().any(|(camera, _)| camera.viewport == *viewport));
+        let bvh = EntityBvh::from_world(world);
+        for (camera, planes) in cameras {
+            let scene = RenderScene { bvh: &bvh, shading: &self.shading, lights: &lights, far: camera.far };
+            let history = (camera.subsampling != Subsampling::Off)
+                .then(|| self.histories.entry(camera.viewport).or_default());
+            render_voxels(
+                scene, &planes, buffer, &mut depth.data, width, camera.viewport, self.tracing_mode, camera.subsampling, history
+            );
         }
     }
+
+    /// Renders a `width` x `height` frame cleared to `background` into freshly allocated buffers
+    pub fn render_offscreen(&mut self, world: &World, width: usize, height: usize, background: u8) -> (Vec<u8>, DepthBuffer) {
+        let mut buffer = vec![background; width * height];
+        let mut depth = DepthBuffer::new(width, height);
+        self.render(world, &mut buffer, width, &mut depth);
+        (buffer, depth)
+    }
 }
 
 impl BaseSystem for VoxelRenderingSystem {
     fn run(&mut self, ctx: &mut RetroBlitContext, world: &mut World, _dt: f32) {
-        let _sw = StopWatch::named("voxels");
         let (sw, sh) = (ctx.get_width(), ctx.get_height());
 
-        let aspect_ratio = sw as f32 / sh as f32;
+        let mut depth = match world.remove_resource::<DepthBuffer>() {
+            Some(depth) if depth.width == sw && depth.height == sh => depth,
+            _ => DepthBuffer::new(sw, sh)
+        };
+        depth.clear();
+        self.renderer.render(world, ctx.get_buffer_mut(), sw, &mut depth);
+        world.insert_resource(depth);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edict::world::World;
+
+    use crate::{
+        components::{Camera, PointLight, Position, Subsampling, ViewAngle},
+        scenes::{demo_materials, spawn_demo_scene, PALETTE_CYCLES, TILES_2D_BYTES},
+        systems::rendering::{
+            shading::{reflected_ray, LightInstance, Shading},
+            subsampling::{phase_count, SampleHistory}
+        },
+        utils::{
+            bvh::EntityBvh,
+            image_writers::save_image,
+            loaders::{create_voxel_model_from_2d_tile, load_palette_cycles},
+            materials::{Material, MaterialTable},
+            palette::cycled_indices,
+            rendering::{camera_basis, gen_frustum_planes, gen_frustum_planes_from_basis, FrustumPlane, Viewport, FAR, NEAR}
+        },
+        voxel_model::VoxelModel
+    };
+
+    use super::{band_lights, is_traced, render_voxels, trace_rows_scalar, RenderScene, RowRays, TracingMode, VoxelRenderer};
 
-        let Some((_, pos, angle)) = world.view::<(&PlayerTag, &Position, &ViewAngle)>()
+    /// Never produced by shading, since the demo palette is much smaller
+    const BACKGROUND: u8 = 255;
+
+    fn demo_world() -> (World, Shading, Vec<LightInstance>) {
+        let (palette, tiles_2d) = retro_blit::format_loaders::im_256::Image::load_from(TILES_2D_BYTES).unwrap();
+        let materials = demo_materials();
+        let mut world = World::new();
+        spawn_demo_scene(&mut world, &tiles_2d, &materials);
+
+        let mut shading = Shading::new(&palette, &[], materials, 1);
+        let lights: Vec<_> = world.view::<(&PointLight, &Position)>()
             .into_iter()
-            .next() else { return; };
+            .map(|(light, pos)| LightInstance { pos: pos.value, light: *light })
+            .collect();
+        for instance in lights.iter() {
+            shading.prepare_tint(instance.light.color_ramp);
+        }
+        (world, shading, lights)
+    }
 
-        let [near_plane, far_plane] = gen_frustum_planes(
-            pos.value.x, pos.value.y, pos.value.z,
-            angle.value,
-            1.125,
-            aspect_ratio
-        );
+    /// Renders `bvh` without point lights into a fresh screen of the size of `viewport` cleared to color 1
+    fn render_unlit(
+        bvh: &EntityBvh,
+        shading: &Shading,
+        planes: &[FrustumPlane; 2],
+        viewport: Viewport,
+        tracing_mode: TracingMode
+    ) -> (Vec<u8>, Vec<f32>) {
+        let scene = RenderScene { bvh, shading, lights: &[], far: FAR };
+        let len = viewport.width * viewport.height;
+        let (mut screen, mut depth) = (vec![1; len], vec![f32::INFINITY; len]);
+        render_voxels(scene, planes, &mut screen, &mut depth, viewport.width, viewport, tracing_mode, Subsampling::Off, None);
+        (screen, depth)
+    }
+
+    fn camera_pose(world: &World) -> (glam::Vec3A, f32) {
+        world.view::<(&Camera, &Position, &ViewAngle)>()
+            .into_iter()
+            .next()
+            .map(|(_, pos, angle)| (pos.value, angle.value))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_packet_tracing_matches_scalar() {
+        let (world, shading, lights) = demo_world();
+        let (pos, angle) = camera_pose(&world);
+        let bvh = EntityBvh::from_world(&world);
+        let scene = RenderScene { bvh: &bvh, shading: &shading, lights: &lights, far: FAR };
+        let viewport = Viewport::full_screen(160, 96);
 
-        let buffer = ctx.get_buffer_mut();
-        let buffer_slice = &mut buffer[0..96*160];
+        for step in 0..12 {
+            let angle = angle + (step as f32 * 30.0).to_radians();
+            let planes = gen_frustum_planes(pos.x, pos.y, pos.z, angle, 1.125, viewport.aspect_ratio());
 
-        fn split_range(range: std::ops::Range<i32>, at: i32) -> (std::ops::Range<i32>, std::ops::Range<i32>) {
-            (range.start..range.start+at, range.start+at..range.end)
+            let (mut scalar, mut scalar_depth) = (vec![BACKGROUND; 96*160], vec![f32::INFINITY; 96*160]);
+            let (mut packets, mut packets_depth) = (vec![BACKGROUND; 96*160], vec![f32::INFINITY; 96*160]);
+            render_voxels(scene, &planes, &mut scalar, &mut scalar_depth, 160, viewport, TracingMode::Scalar, Subsampling::Off, None);
+            render_voxels(scene, &planes, &mut packets, &mut packets_depth, 160, viewport, TracingMode::Packet2x2, Subsampling::Off, None);
+
+            let mismatches = scalar.iter().zip(packets.iter()).filter(|(a, b)| a != b).count();
+            assert_eq!(mismatches, 0, "{} pixels differ at {} degrees", mismatches, step * 30);
+            assert_eq!(scalar_depth, packets_depth, "depth differs at {} degrees", step * 30);
+
+            for (clr, depth) in scalar.iter().zip(scalar_depth.iter()) {
+                assert_eq!(*clr != BACKGROUND, depth.is_finite());
+            }
         }
+    }
 
-        let ((s0, s1), (r0, r1)) = (buffer_slice.split_at_mut(48*160), split_range(0..96, 48));
-
-        let ((s00, s01), (r00, r01)) = (s0.split_at_mut(24*160), split_range(r0, 24));
-        let ((s02, s03), (r02, r03)) = (s1.split_at_mut(24*160), split_range(r1, 24));
-
-        let ((s000, s001), (r000, r001)) = (s00.split_at_mut(12*160), split_range(r00, 12));
-        let ((s002, s003), (r002, r003)) = (s01.split_at_mut(12*160), split_range(r01, 12));
-        let ((s004, s005), (r004, r005)) = (s02.split_at_mut(12*160), split_range(r02, 12));
-        let ((s006, s007), (r006, r007)) = (s03.split_at_mut(12*160), split_range(r03, 12));
-
-        [(r000, s000), (r001, s001), (r002, s002), (r003, s003),
-            (r004, s004), (r005, s005), (r006, s006), (r007, s007)]
-            .into_par_iter()
-            .for_each(|(j_range, slice)| {
-                let mut stride = 0;
-                for j in j_range {
-                    let v = j as f32 / 95.0;
-
-                    let near_left = near_plane.top_left.lerp(near_plane.bottom_left, v);
-                    let near_right = near_plane.top_right.lerp(near_plane.bottom_right, v);
-
-                    let far_left = far_plane.top_left.lerp(far_plane.bottom_left, v);
-                    let far_right = far_plane.top_right.lerp(far_plane.bottom_right, v);
-
-                    let slice_mut = &mut slice[stride..stride+sw];
-
-                    for (i, clr) in slice_mut.iter_mut().enumerate() {
-                        let u = i as f32 / (sw - 1) as f32;
-                        let ray_origin = near_left.lerp(near_right, u);
-                        let far = far_left.lerp(far_right, u);
-                        let ray_dir = (far - ray_origin).normalize();
-
-                        let mut min = None;
-                        for (pos, vox) in world.view::<(&Position, &Voxel)>() {
-                            let mut intersector = VoxelIntersector {
-                                ray_origin,
-                                ray_dir,
-                                pos: pos.value,
-                                min: &mut min
-                            };
-                            vox.data.traverse(&mut intersector);
-                        }
+    #[test]
+    fn test_subsampling_keeps_image() {
+        let (world, shading, lights) = demo_world();
+        let (pos, angle) = camera_pose(&world);
+        let bvh = EntityBvh::from_world(&world);
+        let scene = RenderScene { bvh: &bvh, shading: &shading, lights: &lights, far: FAR };
+        let viewport = Viewport::full_screen(160, 96);
+        let planes = gen_frustum_planes(pos.x, pos.y, pos.z, angle, 1.125, viewport.aspect_ratio());
 
-                        let Some((_t, color_id)) = min else { continue; };
-                        *clr = color_id;
-                    }
-                    stride += sw;
+        let render = |tracing_mode, subsampling, history: Option<&mut SampleHistory>| {
+            let (mut screen, mut depth) = (vec![BACKGROUND; 96 * 160], vec![f32::INFINITY; 96 * 160]);
+            render_voxels(scene, &planes, &mut screen, &mut depth, 160, viewport, tracing_mode, subsampling, history);
+            (screen, depth)
+        };
+        let (full, full_depth) = render(TracingMode::Scalar, Subsampling::Off, None);
+
+        for subsampling in [Subsampling::Checkerboard, Subsampling::Grid2x2] {
+            let (scalar, scalar_depth) = render(TracingMode::Scalar, subsampling, None);
+            let (packets, packets_depth) = render(TracingMode::Packet2x2, subsampling, None);
+            assert_eq!(scalar, packets, "{:?}", subsampling);
+            assert_eq!(scalar_depth, packets_depth, "{:?}", subsampling);
+
+            let mut filled = 0;
+            for (ix, (&clr, &t)) in scalar.iter().zip(scalar_depth.iter()).enumerate() {
+                let (i, j) = (ix % 160, ix / 160);
+                assert_eq!(clr != BACKGROUND, t.is_finite());
+                if is_traced(subsampling, 0, i, j) {
+                    assert_eq!((clr, t), (full[ix], full_depth[ix]), "{:?} at ({}, {})", subsampling, i, j);
+                } else if clr != full[ix] {
+                    filled += 1;
                 }
-            });
+            }
+            // only thin details falling between traced pixels get lost
+            assert!(filled < 160 * 96 / 100, "{:?}: {} pixels differ", subsampling, filled);
+
+            // a view which stays put gets traced all over once the pattern went through its phases
+            let mut history = SampleHistory::default();
+            let first = render(TracingMode::Packet2x2, subsampling, Some(&mut history));
+            assert_eq!(first, (scalar, scalar_depth), "{:?}", subsampling);
+            for _ in 1..phase_count(subsampling) - 1 {
+                render(TracingMode::Packet2x2, subsampling, Some(&mut history));
+            }
+            let last = render(TracingMode::Packet2x2, subsampling, Some(&mut history));
+            assert_eq!(last, (full.clone(), full_depth.clone()), "{:?}", subsampling);
+
+            // once the view moves, nothing traced from where it was gets reused
+            let moved = gen_frustum_planes(pos.x + 8.0, pos.y, pos.z, angle, 1.125, viewport.aspect_ratio());
+            let (mut screen, mut depth) = (vec![BACKGROUND; 96 * 160], vec![f32::INFINITY; 96 * 160]);
+            render_voxels(scene, &moved, &mut screen, &mut depth, 160, viewport, TracingMode::Packet2x2, subsampling, Some(&mut history));
+            let (mut fresh, mut fresh_depth) = (vec![BACKGROUND; 96 * 160], vec![f32::INFINITY; 96 * 160]);
+            render_voxels(scene, &moved, &mut fresh, &mut fresh_depth, 160, viewport, TracingMode::Packet2x2, subsampling, None);
+            assert_eq!((screen, depth), (fresh, fresh_depth), "{:?}", subsampling);
+        }
+    }
+
+    #[test]
+    fn test_viewport_placement() {
+        let (world, shading, lights) = demo_world();
+        let (pos, angle) = camera_pose(&world);
+        let bvh = EntityBvh::from_world(&world);
+        let scene = RenderScene { bvh: &bvh, shading: &shading, lights: &lights, far: FAR };
+
+        // odd sizes on purpose, so partially filled packets and uneven bands get exercised
+        let viewport = Viewport { x: 37, y: 21, width: 71, height: 45 };
+        let planes = gen_frustum_planes(pos.x, pos.y, pos.z, angle, 1.125, viewport.aspect_ratio());
+
+        let mut standalone = vec![BACKGROUND; viewport.width * viewport.height];
+        let mut depth = vec![f32::INFINITY; viewport.width * viewport.height];
+        let full = Viewport::full_screen(viewport.width, viewport.height);
+        render_voxels(scene, &planes, &mut standalone, &mut depth, viewport.width, full, TracingMode::Scalar, Subsampling::Off, None);
+        assert!(standalone.iter().any(|&clr| clr != BACKGROUND));
+
+        for tracing_mode in [TracingMode::Scalar, TracingMode::Packet2x2] {
+            let mut screen = vec![BACKGROUND; 160 * 120];
+            let mut depth = vec![f32::INFINITY; 160 * 120];
+            render_voxels(scene, &planes, &mut screen, &mut depth, 160, viewport, tracing_mode, Subsampling::Off, None);
+
+            for (j, row) in screen.chunks(160).enumerate() {
+                for (i, &clr) in row.iter().enumerate() {
+                    let inside = (viewport.x..viewport.x + viewport.width).contains(&i)
+                        && (viewport.y..viewport.y + viewport.height).contains(&j);
+                    let expected = if inside {
+                        standalone[(j - viewport.y) * viewport.width + i - viewport.x]
+                    } else {
+                        BACKGROUND
+                    };
+                    assert_eq!(clr, expected, "{:?} at ({}, {})", tracing_mode, i, j);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_light_culling_keeps_image() {
+        let (world, shading, mut lights) = demo_world();
+        let (pos, angle) = camera_pose(&world);
+        let bvh = EntityBvh::from_world(&world);
+        let viewport = Viewport::full_screen(160, 96);
+        let planes = gen_frustum_planes(pos.x, pos.y, pos.z, angle, 1.125, viewport.aspect_ratio());
+
+        // in front of the sphere, which is the one lit surface in view of the demo camera
+        let light = PointLight { radius: 48.0, intensity: 1.0, color_ramp: 5 };
+        lights.push(LightInstance { pos: glam::vec3a(-16.0, 16.0, 140.0), light });
+
+        let render = |lights: &[LightInstance]| {
+            let scene = RenderScene { bvh: &bvh, shading: &shading, lights, far: FAR };
+            let (mut screen, mut depth) = (vec![BACKGROUND; 96 * 160], vec![f32::INFINITY; 96 * 160]);
+            render_voxels(scene, &planes, &mut screen, &mut depth, 160, viewport, TracingMode::Packet2x2, Subsampling::Off, None);
+            screen
+        };
+        let culled = render(&lights);
+        assert_ne!(culled, render(&[]), "the light should be visible");
+
+        // a single band holding every light, so nothing gets culled
+        let scene = RenderScene { bvh: &bvh, shading: &shading, lights: &lights, far: FAR };
+        let (mut unculled, mut depth) = (vec![BACKGROUND; 96 * 160], vec![f32::INFINITY; 96 * 160]);
+        trace_rows_scalar(scene, &planes, viewport, 0..96, &mut unculled, &mut depth, 160);
+        assert_eq!(culled, unculled);
+
+        // a light behind the camera is culled from the whole view, the one in front of the sphere is not
+        let behind = LightInstance { pos: pos - glam::Vec3A::Z * 64.0, light: PointLight { radius: 32.0, ..light } };
+        let kept = band_lights(&[behind, lights[lights.len() - 1]], &planes, viewport, 0..96);
+        assert_eq!(kept.iter().map(|instance| instance.pos).collect::<Vec<_>>(), vec![glam::vec3a(-16.0, 16.0, 140.0)]);
+    }
+
+    #[test]
+    fn test_translucent_water() {
+        let (palette, tiles_2d) = retro_blit::format_loaders::im_256::Image::load_from(TILES_2D_BYTES).unwrap();
+        let water = create_voxel_model_from_2d_tile(&tiles_2d, 64, 64);
+        let sphere = VoxelModel::make_sphere32x32x32(0, 23);
+        let items = [(glam::vec3a(0.0, 0.0, 0.0), &water), (glam::vec3a(0.0, -40.0, 0.0), &sphere)];
+        let bvh = EntityBvh::build(items);
+
+        // looking straight down at the water with the sphere below it
+        let viewport = Viewport::full_screen(32, 32);
+        let planes = gen_frustum_planes_from_basis(
+            glam::vec3a(16.0, 40.0, 16.0),
+            camera_basis(0.0, -90.0f32.to_radians(), 0.0),
+            0.25,
+            viewport.aspect_ratio(),
+            NEAR,
+            FAR
+        );
+
+        let render = |shading: &Shading, tracing_mode| render_unlit(&bvh, shading, &planes, viewport, tracing_mode);
+
+        let opaque = Shading::new(&palette, &[], MaterialTable::default(), 1);
+        let translucent = Shading::new(&palette, &[], demo_materials(), 1);
+        let (opaque_screen, opaque_depth) = render(&opaque, TracingMode::Scalar);
+        let (scalar_screen, scalar_depth) = render(&translucent, TracingMode::Scalar);
+        let (packet_screen, _) = render(&translucent, TracingMode::Packet2x2);
+
+        assert_eq!(scalar_screen, packet_screen);
+        // the water surface stays in the depth buffer, while the colors change where the sphere shows through
+        assert_eq!(scalar_depth, opaque_depth);
+        let changed = scalar_screen.iter().zip(opaque_screen.iter()).filter(|(a, b)| a != b).count();
+        assert!(changed > 32 * 32 / 4, "only {} pixels changed", changed);
+
+        // with the shipped palette cycles the water is drawn with the entries they rotate, so it animates
+        let cycled = Shading::new(&palette, &cycled_indices(&load_palette_cycles(PALETTE_CYCLES)), demo_materials(), 1);
+        let (cycled_screen, _) = render(&cycled, TracingMode::Packet2x2);
+        let animated = cycled_screen.iter().filter(|clr| (17..=19).contains(*clr)).count();
+        assert!(animated > 32 * 32 / 4, "only {} pixels show the water cycle", animated);
+    }
+
+    #[test]
+    fn test_reflections() {
+        let (palette, _) = retro_blit::format_loaders::im_256::Image::load_from(TILES_2D_BYTES).unwrap();
+        let mirror = VoxelModel::make_32x32x32(|_, y, _| if y < 4 { 18 } else { 0 });
+        let sphere = VoxelModel::make_sphere32x32x32(0, 23);
+        let items = [(glam::vec3a(0.0, 0.0, 0.0), &mirror), (glam::vec3a(0.0, 8.0, 24.0), &sphere)];
+        let bvh = EntityBvh::build(items);
+
+        // looking down at the mirror with the sphere standing on its far edge
+        let viewport = Viewport::full_screen(48, 32);
+        let planes = gen_frustum_planes_from_basis(
+            glam::vec3a(16.0, 24.0, -24.0),
+            camera_basis(0.0, -30.0f32.to_radians(), 0.0),
+            0.75,
+            viewport.aspect_ratio(),
+            NEAR,
+            FAR
+        );
+
+        let render = |shading: &Shading, tracing_mode| render_unlit(&bvh, shading, &planes, viewport, tracing_mode);
+
+        // a glowing sphere keeps its color wherever it is seen
+        let glowing = MaterialTable::default().with(23, Material { emissive: true, ..Material::default() });
+        let reflective = glowing.clone().with(18, Material { reflective: true, ..Material::default() });
+        let matte = Shading::new(&palette, &[], glowing, 1);
+        let mut mirrored = Shading::new(&palette, &[], reflective, 1);
+        let (matte_screen, matte_depth) = render(&matte, TracingMode::Scalar);
+        let (scalar_screen, scalar_depth) = render(&mirrored, TracingMode::Scalar);
+        let (packet_screen, _) = render(&mirrored, TracingMode::Packet2x2);
+
+        assert_eq!(scalar_screen, packet_screen);
+        assert_eq!(scalar_depth, matte_depth);
+
+        // the ray of this pixel is mirrored onto the sphere, which gets blended over the mirror
+        let (i, j) = (24, 16);
+        let ray = RowRays::new(&planes, j, viewport.height).ray(i, viewport.width);
+        let hit = bvh.cast_ray(ray.0, ray.1).unwrap();
+        let reflected = reflected_ray(ray, &hit).unwrap();
+        assert_eq!((hit.color_id, bvh.cast_ray(reflected.0, reflected.1).map(|hit| hit.color_id)), (18, Some(23)));
+        let ix = j * viewport.width + i;
+        assert_eq!(scalar_screen[ix], mirrored.blend_table.get(matte_screen[ix], 23));
+        assert_ne!(scalar_screen[ix], matte_screen[ix]);
+
+        // without bounces left the mirror is drawn like any other surface
+        mirrored.max_bounces = 0;
+        assert_eq!(render(&mirrored, TracingMode::Scalar).0, matte_screen);
+    }
+
+    #[test]
+    fn test_headless_rendering() {
+        let (palette, tiles_2d) = retro_blit::format_loaders::im_256::Image::load_from(TILES_2D_BYTES).unwrap();
+        let materials = demo_materials();
+        let mut world = World::new();
+        spawn_demo_scene(&mut world, &tiles_2d, &materials);
+
+        let mut renderer = VoxelRenderer::new(&palette, &[], materials, 1);
+        let (frame, depth) = renderer.render_offscreen(&world, 160, 120, BACKGROUND);
+
+        // the demo camera covers the top 96 rows, leaving the rest of the frame alone
+        assert!(frame[..160 * 96].iter().any(|&clr| clr != BACKGROUND));
+        assert!(frame[160 * 96..].iter().all(|&clr| clr == BACKGROUND));
+        for (clr, t) in frame.iter().zip(depth.data.iter()) {
+            assert_eq!(*clr != BACKGROUND, t.is_finite());
+        }
+
+        // too small for the demo camera, so nothing gets drawn
+        let (small, _) = renderer.render_offscreen(&world, 80, 60, BACKGROUND);
+        assert!(small.iter().all(|&clr| clr == BACKGROUND));
 
-        // self.font.draw_text_in_box(
-        //     ctx,
-        //     0, 0,
-        //     160, 20,
-        //     HorizontalAlignment::Center,
-        //     VerticalAlignment::Center,
-        //     &format!("{}", pos.value.z),
-        //     Some(21)
-        // );
+        let path = std::env::temp_dir().join(format!("voxely_headless_{}.bmp", std::process::id()));
+        save_image(&path, 160, 120, &frame, &palette).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 14 + 40 + 256 * 4 + 160 * 120);
+        std::fs::remove_file(&path).unwrap();
+        assert!(save_image(path.with_extension("gif"), 160, 120, &frame, &palette).is_err());
     }
 }
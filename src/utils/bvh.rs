@@ -0,0 +1,446 @@
+use edict::world::World;
+use glam::{vec3a, BVec4A, Vec3A, Vec4};
+
+use crate::{
+    components::{Position, Rotation, Scale, Voxel},
+    utils::{
+        materials::MaterialTable,
+        ray_packets::{cast_ray_packet_to_box, RayPacket4, VoxelPacketIntersector, PACKET_WIDTH},
+        ray_queries::{cast_ray_to_box, Face, ModelTransform, RayHit, VoxelIntersector, VoxelOccluder}
+    },
+    voxel_model::VoxelModel
+};
+
+const MAX_LEAF_ITEMS: usize = 2;
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BvhItem<'a> {
+    pub pos: Vec3A,
+    pub model: &'a VoxelModel,
+    /// Position of the item in the original input, used to break ties between equally distant hits
+    pub order: usize,
+    pub transform: Option<ModelTransform>
+}
+
+impl<'a> BvhItem<'a> {
+    fn size(&self) -> Vec3A {
+        let [w, h, d] = self.model.size;
+        vec3a(w as f32, h as f32, d as f32)
+    }
+
+    fn bounds(&self) -> (Vec3A, Vec3A) {
+        match self.transform {
+            Some(transform) => transform.bounds(self.pos, self.size()),
+            None => (self.pos, self.pos + self.size())
+        }
+    }
+
+    /// The ray along with the item position to traverse the model with
+    #[inline(always)]
+    fn model_ray(&self, ray_origin: Vec3A, ray_dir: Vec3A) -> (Vec3A, Vec3A, Vec3A) {
+        match self.transform {
+            Some(transform) => {
+                let (ray_origin, ray_dir) = transform.ray_to_model(self.pos, self.size(), ray_origin, ray_dir);
+                (ray_origin, ray_dir, Vec3A::ZERO)
+            },
+            None => (ray_origin, ray_dir, self.pos)
+        }
+    }
+
+    #[inline(always)]
+    fn normal_to_world(&self, normal: Vec3A) -> Vec3A {
+        self.transform.map_or(normal, |transform| transform.normal_to_world(normal))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BvhNodeKind {
+    Leaf { first: usize, count: usize },
+    /// the left child always directly follows its parent
+    Inner { right: usize }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    min: Vec3A,
+    max: Vec3A,
+    kind: BvhNodeKind
+}
+
+/// Bounding volume hierarchy over the boxes of voxel entities.
+///
+/// It borrows the models it was built from, so it is meant to be rebuilt every frame, which is
+/// cheap next to tracing a frame worth of rays. Hits are resolved the same way as iterating all
+/// items in their original order: the closest hit wins and equally distant hits go to the item
+/// that came first. That keeps results independent of traversal order, so scalar and packet
+/// queries always agree.
+pub struct EntityBvh<'a> {
+    items: Vec<BvhItem<'a>>,
+    nodes: Vec<BvhNode>
+}
+
+/// Smallest float above `t`, used as an inclusive bound for per-item queries. `t` is never negative.
+#[inline(always)]
+fn next_after(t: f32) -> f32 {
+    if t.is_finite() { f32::from_bits(t.abs().to_bits() + 1) } else { t }
+}
+
+impl<'a> EntityBvh<'a> {
+    pub fn build(items: impl IntoIterator<Item = (Vec3A, &'a VoxelModel)>) -> Self {
+        Self::build_transformed(items.into_iter().map(|(pos, model)| (pos, model, None)))
+    }
+
+    /// [`build`](Self::build) for models that may be rotated or scaled
+    pub fn build_transformed(items: impl IntoIterator<Item = (Vec3A, &'a VoxelModel, Option<ModelTransform>)>) -> Self {
+        let mut items: Vec<BvhItem<'a>> = items
+            .into_iter()
+            .enumerate()
+            .map(|(order, (pos, model, transform))| BvhItem { pos, model, order, transform })
+            .collect();
+        let mut nodes = Vec::with_capacity(items.len() * 2);
+        if !items.is_empty() {
+            Self::build_node(&mut nodes, &mut items, 0);
+        }
+        Self { items, nodes }
+    }
+
+    pub fn from_world(world: &'a World) -> Self {
+        Self::build_transformed(
+            world
+                .view::<(&Position, &Voxel, Option<&Rotation>, Option<&Scale>)>()
+                .into_iter()
+                .map(|(pos, vox, rotation, scale)| (pos.value, &vox.data, ModelTransform::from_components(rotation, scale)))
+        )
+    }
+
+    pub fn items(&self) -> &[BvhItem<'a>] {
+        &self.items
+    }
+
+    fn build_node(nodes: &mut Vec<BvhNode>, items: &mut [BvhItem<'a>], first: usize) -> usize {
+        let (mut min, mut max) = (Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY));
+        let (mut centroid_min, mut centroid_max) = (min, max);
+        for item in items.iter() {
+            let (p0, p1) = item.bounds();
+            min = min.min(p0);
+            max = max.max(p1);
+            centroid_min = centroid_min.min((p0 + p1) * 0.5);
+            centroid_max = centroid_max.max((p0 + p1) * 0.5);
+        }
+
+        let ix = nodes.len();
+        nodes.push(BvhNode { min, max, kind: BvhNodeKind::Leaf { first, count: items.len() } });
+        if items.len() <= MAX_LEAF_ITEMS { return ix; }
+
+        let extent = centroid_max - centroid_min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        items.sort_by(|a, b| {
+            let (a0, a1) = a.bounds();
+            let (b0, b1) = b.bounds();
+            (a0[axis] + a1[axis]).total_cmp(&(b0[axis] + b1[axis]))
+        });
+
+        let mid = items.len() / 2;
+        let (left, right) = items.split_at_mut(mid);
+        Self::build_node(nodes, left, first);
+        let right = Self::build_node(nodes, right, first + mid);
+        nodes[ix].kind = BvhNodeKind::Inner { right };
+        ix
+    }
+
+    #[inline(always)]
+    fn cast_ray_to_node(&self, ray_origin: Vec3A, ray_dir: Vec3A, ix: usize) -> Option<f32> {
+        let node = &self.nodes[ix];
+        cast_ray_to_box(ray_origin, ray_dir, node.min, node.max - node.min).map(|(t_enter, _)| t_enter)
+    }
+
+    /// Lanes of the packet entering node `ix`, and where they enter it
+    #[inline(always)]
+    fn cast_packet_to_node(&self, packet: &RayPacket4, ix: usize) -> (BVec4A, Vec4) {
+        let node = &self.nodes[ix];
+        let (hit, t_enter, _) = cast_ray_packet_to_box(packet, node.min, node.max - node.min);
+        (hit, t_enter)
+    }
+
+    /// Closest hit along the ray, visiting entities front to back.
+    /// Used by the renderer and by gameplay raycasts alike.
+    pub fn cast_ray(&self, ray_origin: Vec3A, ray_dir: Vec3A) -> Option<RayHit> {
+        self.cast_ray_through(ray_origin, ray_dir, None)
+    }
+
+    /// [`cast_ray`](Self::cast_ray) passing through translucent voxels when `see_through` is given,
+    /// used to find what lies behind them
+    pub fn cast_ray_through(&self, ray_origin: Vec3A, ray_dir: Vec3A, see_through: Option<&MaterialTable>) -> Option<RayHit> {
+        let mut best: Option<(RayHit, usize)> = None;
+
+        if self.nodes.is_empty() { return None; }
+        let t = self.cast_ray_to_node(ray_origin, ray_dir, 0)?;
+        let mut stack = [(0, 0.0); MAX_DEPTH];
+        stack[0] = (0, t);
+        let mut stack_len = 1;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (ix, t_node) = stack[stack_len];
+            if matches!(best, Some((best, _)) if t_node > best.t) { continue; }
+
+            match self.nodes[ix].kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for item in &self.items[first..first + count] {
+                        let mut min = best.map(|(best, _)| RayHit { t: next_after(best.t), color_id: 0, normal: Vec3A::ZERO });
+                        let (model_origin, model_dir, pos) = item.model_ray(ray_origin, ray_dir);
+                        item.model.traverse(&mut VoxelIntersector {
+                            ray_origin: model_origin,
+                            ray_dir: model_dir,
+                            pos,
+                            min: &mut min,
+                            see_through
+                        });
+                        let Some(mut hit) = min else { continue; };
+                        if hit.color_id == 0 { continue; }
+                        hit.normal = item.normal_to_world(hit.normal);
+                        match best {
+                            Some((best, order)) if hit.t > best.t || (hit.t == best.t && order < item.order) => (),
+                            _ => best = Some((hit, item.order))
+                        }
+                    }
+                },
+                BvhNodeKind::Inner { right } => {
+                    let left = ix + 1;
+                    let hits = [
+                        (left, self.cast_ray_to_node(ray_origin, ray_dir, left)),
+                        (right, self.cast_ray_to_node(ray_origin, ray_dir, right))
+                    ];
+                    let [near, far] = match hits {
+                        [(_, Some(t_left)), (_, Some(t_right))] if t_right < t_left => [hits[1], hits[0]],
+                        _ => hits
+                    };
+                    // the nearer child is pushed last so it gets popped first
+                    for (child, t) in [far, near] {
+                        let Some(t) = t else { continue; };
+                        stack[stack_len] = (child, t);
+                        stack_len += 1;
+                    }
+                }
+            }
+        }
+
+        best.map(|(hit, _)| hit)
+    }
+
+    /// Whether any solid voxel is entered before `max_t`, without looking for the closest one
+    pub fn is_occluded(&self, ray_origin: Vec3A, ray_dir: Vec3A, max_t: f32) -> bool {
+        if self.nodes.is_empty() { return false; }
+
+        let mut stack = [0; MAX_DEPTH];
+        let mut stack_len = 1;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let ix = stack[stack_len];
+            match self.cast_ray_to_node(ray_origin, ray_dir, ix) {
+                Some(t_enter) if t_enter < max_t => (),
+                _ => continue
+            }
+
+            match self.nodes[ix].kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for item in &self.items[first..first + count] {
+                        let (ray_origin, ray_dir, pos) = item.model_ray(ray_origin, ray_dir);
+                        let mut occluder = VoxelOccluder { ray_origin, ray_dir, pos, max_t, occluded: false };
+                        item.model.traverse(&mut occluder);
+                        if occluder.occluded { return true; }
+                    }
+                },
+                BvhNodeKind::Inner { right } => {
+                    stack[stack_len] = right;
+                    stack[stack_len + 1] = ix + 1;
+                    stack_len += 2;
+                }
+            }
+        }
+        false
+    }
+
+    /// Packet counterpart of [`cast_ray`](Self::cast_ray), following the conventions of
+    /// [`VoxelPacketIntersector`] for `min_t` and `color_ids`, with the world space normals of the
+    /// entered faces written to `normals`.
+    pub fn cast_ray_packet(
+        &self,
+        packet: &RayPacket4,
+        min_t: &mut Vec4,
+        color_ids: &mut [u8; PACKET_WIDTH],
+        normals: &mut [Vec3A; PACKET_WIDTH]
+    ) {
+        if self.nodes.is_empty() { return; }
+
+        let mut orders = [usize::MAX; PACKET_WIDTH];
+        let (hit, t_enter) = self.cast_packet_to_node(packet, 0);
+        let mut stack = [(0, hit, t_enter); MAX_DEPTH];
+        let mut stack_len = 1;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (ix, hit, t_enter) = stack[stack_len];
+            if !(hit & t_enter.cmple(*min_t)).any() { continue; }
+
+            match self.nodes[ix].kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for item in &self.items[first..first + count] {
+                        let mut item_t = Vec4::from(min_t.to_array().map(next_after));
+                        let (mut item_color_ids, mut item_faces) = ([0; PACKET_WIDTH], [Face::Inside; PACKET_WIDTH]);
+                        let model_packet = item.transform.map(|_| {
+                            RayPacket4::from_rays([0, 1, 2, 3].map(|lane| {
+                                let (ray_origin, ray_dir) = packet.ray(lane);
+                                let (ray_origin, ray_dir, _) = item.model_ray(ray_origin, ray_dir);
+                                (ray_origin, ray_dir)
+                            }))
+                        });
+                        item.model.traverse(&mut VoxelPacketIntersector {
+                            packet: model_packet.as_ref().unwrap_or(packet),
+                            pos: if model_packet.is_some() { Vec3A::ZERO } else { item.pos },
+                            min_t: &mut item_t,
+                            color_ids: &mut item_color_ids,
+                            faces: &mut item_faces
+                        });
+                        for lane in 0..PACKET_WIDTH {
+                            let (t, color_id) = (item_t[lane], item_color_ids[lane]);
+                            if color_id == 0 { continue; }
+                            if t < min_t[lane] || (t == min_t[lane] && item.order < orders[lane]) {
+                                min_t[lane] = t;
+                                color_ids[lane] = color_id;
+                                normals[lane] = item.normal_to_world(item_faces[lane].normal());
+                                orders[lane] = item.order;
+                            }
+                        }
+                    }
+                },
+                BvhNodeKind::Inner { right } => {
+                    let left = ix + 1;
+                    let children = [left, right].map(|child| {
+                        let (hit, t_enter) = self.cast_packet_to_node(packet, child);
+                        // the packet enters a child where its first lane still looking for a hit does
+                        let active = hit & t_enter.cmple(*min_t);
+                        let entry = Vec4::select(active, t_enter, Vec4::splat(f32::INFINITY)).min_element();
+                        (child, hit, t_enter, entry)
+                    });
+                    let [near, far] = if children[1].3 < children[0].3 { [children[1], children[0]] } else { children };
+                    // the nearer child is pushed last so it gets popped first
+                    for (child, hit, t_enter, entry) in [far, near] {
+                        if entry == f32::INFINITY { continue; }
+                        stack[stack_len] = (child, hit, t_enter);
+                        stack_len += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use glam::{vec3a, Quat, Vec3A, Vec4};
+
+    use crate::{
+        utils::{ray_packets::RayPacket4, ray_queries::{ModelTransform, VoxelIntersector}},
+        voxel_model::VoxelModel
+    };
+
+    use super::EntityBvh;
+
+    #[test]
+    fn test_bvh_matches_brute_force() {
+        let spheres = [VoxelModel::make_sphere32x32x32(0, 5), VoxelModel::make_sphere32x32x32(0, 7)];
+        // a grid with touching and overlapping neighbours so equally distant hits happen
+        let items: Vec<(Vec3A, &VoxelModel)> = (0..64)
+            .map(|i| {
+                let pos = vec3a((i % 4) as f32 * 32.0, (i / 4 % 4) as f32 * 24.0, (i / 16) as f32 * 40.0);
+                (pos - vec3a(64.0, 48.0, -40.0), &spheres[i % 2])
+            })
+            .collect();
+        let bvh = EntityBvh::build(items.iter().cloned());
+
+        for j in 0..24 {
+            for i in (0..32).step_by(4) {
+                let rays = [0, 1, 2, 3].map(|lane| {
+                    let ray_dir = vec3a((i + lane) as f32 - 16.0, j as f32 - 12.0, 24.0).normalize();
+                    (Vec3A::ZERO, ray_dir)
+                });
+
+                let (mut min_t, mut color_ids, mut normals) = (Vec4::splat(f32::INFINITY), [0; 4], [Vec3A::ZERO; 4]);
+                bvh.cast_ray_packet(&RayPacket4::from_rays(rays), &mut min_t, &mut color_ids, &mut normals);
+
+                for (lane, (ray_origin, ray_dir)) in rays.into_iter().enumerate() {
+                    let mut expected = None;
+                    for (pos, model) in items.iter() {
+                        model.traverse(&mut VoxelIntersector { ray_origin, ray_dir, pos: *pos, min: &mut expected, see_through: None });
+                    }
+
+                    assert_eq!(bvh.cast_ray(ray_origin, ray_dir), expected);
+                    assert_eq!(color_ids[lane], expected.map_or(0, |hit| hit.color_id));
+                    if let Some(hit) = expected {
+                        assert_eq!(min_t[lane], hit.t);
+                        assert_eq!(normals[lane], hit.normal);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_transformed_models() {
+        // the bottom quarter of the model is solid
+        let slab = VoxelModel::make_32x32x32(|_, y, _| if y < 8 { 5 } else { 0 });
+        let sphere = VoxelModel::make_sphere32x32x32(0, 7);
+
+        // turned about the model center, the slab ends up on the +X side facing -X
+        let turned = ModelTransform { rotation: Quat::from_rotation_z(90.0f32.to_radians()), scale: 1.0 };
+        let bvh = EntityBvh::build_transformed([(Vec3A::ZERO, &slab, Some(turned))]);
+        let hit = bvh.cast_ray(vec3a(-100.0, 16.5, 16.5), Vec3A::X).unwrap();
+        assert_eq!(hit.color_id, 5);
+        assert!((hit.t - 124.0).abs() < 1e-3);
+        assert!(hit.normal.abs_diff_eq(-Vec3A::X, 1e-5));
+
+        // scaling grows the model away from its position, the first solid voxel on this line is at z = 1
+        let doubled = ModelTransform { rotation: Quat::IDENTITY, scale: 2.0 };
+        let bvh = EntityBvh::build_transformed([(vec3a(0.0, 0.0, 10.0), &sphere, Some(doubled))]);
+        let hit = bvh.cast_ray(vec3a(33.0, 33.0, -100.0), Vec3A::Z).unwrap();
+        assert!((hit.t - 112.0).abs() < 1e-3);
+        assert!(!bvh.is_occluded(vec3a(33.0, 33.0, -100.0), Vec3A::Z, 111.0));
+        assert!(bvh.is_occluded(vec3a(33.0, 33.0, -100.0), Vec3A::Z, 113.0));
+
+        // packets agree with single rays on arbitrarily turned and scaled models
+        let items: Vec<_> = (0..8)
+            .map(|i| {
+                let rotation = Quat::from_euler(glam::EulerRot::YXZ, i as f32 * 0.7, i as f32 * 0.3, i as f32 * 0.5);
+                let transform = ModelTransform { rotation, scale: 0.5 + i as f32 * 0.25 };
+                let pos = vec3a((i % 4) as f32 * 40.0 - 80.0, (i / 4) as f32 * 40.0 - 40.0, 80.0);
+                (pos, if i % 2 == 0 { &slab } else { &sphere }, Some(transform))
+            })
+            .collect();
+        let bvh = EntityBvh::build_transformed(items);
+        let mut hits = 0;
+        for j in 0..32 {
+            for i in (0..48).step_by(4) {
+                let rays = [0, 1, 2, 3].map(|lane| {
+                    (Vec3A::ZERO, vec3a((i + lane) as f32 - 24.0, j as f32 - 16.0, 24.0).normalize())
+                });
+                let (mut min_t, mut color_ids, mut normals) = (Vec4::splat(f32::INFINITY), [0; 4], [Vec3A::ZERO; 4]);
+                bvh.cast_ray_packet(&RayPacket4::from_rays(rays), &mut min_t, &mut color_ids, &mut normals);
+
+                for (lane, (ray_origin, ray_dir)) in rays.into_iter().enumerate() {
+                    let expected = bvh.cast_ray(ray_origin, ray_dir);
+                    assert_eq!(color_ids[lane], expected.map_or(0, |hit| hit.color_id));
+                    if let Some(hit) = expected {
+                        hits += 1;
+                        assert_eq!(min_t[lane], hit.t);
+                        assert_eq!(normals[lane], hit.normal);
+                        assert!(hit.normal.dot(ray_dir) <= 0.0);
+                    }
+                }
+            }
+        }
+        assert!(hits > 0);
+    }
+}
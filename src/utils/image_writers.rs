@@ -0,0 +1,109 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path
+};
+
+/// Writes an indexed image as a binary PPM, expanding pixels through `palette`.
+/// Indices missing from the palette come out black.
+pub fn write_ppm(out: &mut impl Write, width: usize, height: usize, pixels: &[u8], palette: &[[u8; 3]]) -> io::Result<()> {
+    assert_eq!(pixels.len(), width * height);
+
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+    let rgb: Vec<u8> = pixels
+        .iter()
+        .flat_map(|&ix| palette.get(ix as usize).copied().unwrap_or([0, 0, 0]))
+        .collect();
+    out.write_all(&rgb)
+}
+
+/// Writes an 8-bit paletted BMP, keeping the pixels as palette indices
+pub fn write_bmp(out: &mut impl Write, width: usize, height: usize, pixels: &[u8], palette: &[[u8; 3]]) -> io::Result<()> {
+    assert_eq!(pixels.len(), width * height);
+
+    const HEADERS_SIZE: u32 = 14 + 40;
+    const PALETTE_SIZE: u32 = 256 * 4;
+    // rows are padded to a multiple of 4 bytes
+    let row_size = width.div_ceil(4) * 4;
+    let data_offset = HEADERS_SIZE + PALETTE_SIZE;
+    let file_size = data_offset + (row_size * height) as u32;
+
+    // file header
+    out.write_all(b"BM")?;
+    out.write_all(&file_size.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&data_offset.to_le_bytes())?;
+
+    // BITMAPINFOHEADER, uncompressed with a full 256 entry palette
+    out.write_all(&40u32.to_le_bytes())?;
+    out.write_all(&(width as i32).to_le_bytes())?;
+    out.write_all(&(height as i32).to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&8u16.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&((row_size * height) as u32).to_le_bytes())?;
+    out.write_all(&2835i32.to_le_bytes())?;
+    out.write_all(&2835i32.to_le_bytes())?;
+    out.write_all(&256u32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+
+    for ix in 0..256 {
+        let [r, g, b] = palette.get(ix).copied().unwrap_or([0, 0, 0]);
+        out.write_all(&[b, g, r, 0])?;
+    }
+
+    // stored bottom up
+    let padding = [0u8; 3];
+    for row in pixels.chunks(width.max(1)).rev() {
+        out.write_all(row)?;
+        out.write_all(&padding[..row_size - width])?;
+    }
+    Ok(())
+}
+
+/// Saves an indexed image, picking the format from the `ppm` or `bmp` extension of `path`
+pub fn save_image(path: impl AsRef<Path>, width: usize, height: usize, pixels: &[u8], palette: &[[u8; 3]]) -> io::Result<()> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+    let bmp = match extension.as_deref() {
+        Some("ppm") => false,
+        Some("bmp") => true,
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported image format of {}", path.display())
+        ))
+    };
+
+    let mut out = BufWriter::new(File::create(path)?);
+    if bmp {
+        write_bmp(&mut out, width, height, pixels, palette)?;
+    } else {
+        write_ppm(&mut out, width, height, pixels, palette)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_bmp, write_ppm};
+
+    #[test]
+    fn test_image_writers() {
+        let palette = [[10, 20, 30], [200, 100, 50]];
+        let pixels = [0, 1, 1, 1, 0, 0];
+
+        let mut ppm = Vec::new();
+        write_ppm(&mut ppm, 3, 2, &pixels, &palette).unwrap();
+        assert_eq!(&ppm[..11], b"P6\n3 2\n255\n");
+        assert_eq!(&ppm[11..], &[10, 20, 30, 200, 100, 50, 200, 100, 50, 200, 100, 50, 10, 20, 30, 10, 20, 30]);
+
+        let mut bmp = Vec::new();
+        write_bmp(&mut bmp, 3, 2, &pixels, &palette).unwrap();
+        let offset = 14 + 40 + 256 * 4;
+        assert_eq!(bmp.len(), offset + 2 * 4);
+        assert_eq!(u32::from_le_bytes(bmp[2..6].try_into().unwrap()) as usize, bmp.len());
+        assert_eq!(&bmp[54..62], &[30, 20, 10, 0, 50, 100, 200, 0]);
+        // bottom row first, each padded to 4 bytes
+        assert_eq!(&bmp[offset..], &[1, 0, 0, 0, 0, 1, 1, 0]);
+    }
+}
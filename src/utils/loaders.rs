@@ -2,7 +2,10 @@ use std::io::{Cursor, Read, Seek, SeekFrom};
 
 use retro_blit::rendering::blittable::{BufferProvider, SizedSurface};
 
-use crate::voxel_model::{VoxelData, VoxelModel};
+use crate::{
+    utils::palette::PaletteCycle,
+    voxel_model::{VoxelData, VoxelModel}
+};
 
 pub fn create_voxel_model_from_2d_tile(
     tiles_2d: &retro_blit::rendering::BlittableSurface,
@@ -132,3 +135,48 @@ pub fn load_xraw(bytes: &[u8]) -> VoxelModel {
     }).compact();
     VoxelModel { size: [width as usize, height as usize, depth as usize], data }
 }
+
+/// Parses palette cycles, one per line as `name speed index...`, skipping blank lines and `#` comments
+pub fn load_palette_cycles(text: &str) -> Vec<PaletteCycle> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next().unwrap().to_string();
+            let speed = fields.next()
+                .and_then(|speed| speed.parse().ok())
+                .unwrap_or_else(|| panic!("palette cycle `{}` has no valid speed", name));
+            let indices: Vec<u8> = fields
+                .map(|ix| ix.parse().unwrap_or_else(|_| panic!("palette cycle `{}` has a bad index `{}`", name, ix)))
+                .collect();
+            PaletteCycle { name, speed, indices }
+        })
+        .collect()
+}
+
+/// Reads an uncompressed 8-bit BMP, such as the ones written by
+/// [`write_bmp`](super::image_writers::write_bmp), as `(width, height, pixels)` with the pixels
+/// kept as palette indices in top down order
+pub fn load_indexed_bmp(bytes: &[u8]) -> (usize, usize, Vec<u8>) {
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let u16_at = |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+
+    assert_eq!(&bytes[..2], b"BM");
+    assert_eq!(u16_at(28), 8, "only 8-bit images are supported");
+    assert_eq!(u32_at(30), 0, "only uncompressed images are supported");
+
+    let data_offset = u32_at(10) as usize;
+    let width = u32_at(18) as usize;
+    let height = u32_at(22) as i32;
+    let row_size = width.div_ceil(4) * 4;
+
+    let mut pixels = Vec::with_capacity(width * height.unsigned_abs() as usize);
+    for j in 0..height.unsigned_abs() as usize {
+        // positive heights are stored bottom up
+        let row = if height > 0 { height as usize - 1 - j } else { j };
+        let start = data_offset + row * row_size;
+        pixels.extend_from_slice(&bytes[start..start + width]);
+    }
+    (width, height.unsigned_abs() as usize, pixels)
+}
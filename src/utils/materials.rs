@@ -0,0 +1,98 @@
+use crate::{
+    components::PointLight,
+    voxel_model::{VoxelData, VoxelDataVisitor, VoxelModel}
+};
+
+/// Light cast on nearby surfaces by models containing a glowing material
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Glow {
+    pub radius: f32,
+    pub intensity: f32
+}
+
+/// How voxels of a single palette entry are rendered
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Material {
+    /// drawn at full brightness, untouched by lighting and fog
+    pub emissive: bool,
+    pub glow: Option<Glow>,
+    /// blended over whatever lies behind instead of hiding it
+    pub translucent: bool,
+    /// mixes in what a ray mirrored about the face shows
+    pub reflective: bool
+}
+
+/// Materials of all palette entries, indexed by `color_id`
+#[derive(Clone)]
+pub struct MaterialTable {
+    materials: Vec<Material>
+}
+
+impl Default for MaterialTable {
+    fn default() -> Self {
+        Self { materials: vec![Material::default(); 256] }
+    }
+}
+
+struct GlowFinder<'a> {
+    materials: &'a MaterialTable,
+    strongest: Option<(u8, Glow)>
+}
+
+impl<'a> VoxelDataVisitor for GlowFinder<'a> {
+    fn visit(&mut self, _min_c: &[usize], _max_c: &[usize], data: &VoxelData) -> bool {
+        let &VoxelData::Leaf { color_id } = data else { return true; };
+        if let Some(glow) = self.materials.get(color_id).glow {
+            if !matches!(self.strongest, Some((_, strongest)) if strongest.intensity >= glow.intensity) {
+                self.strongest = Some((color_id, glow));
+            }
+        }
+        false
+    }
+}
+
+impl MaterialTable {
+    pub fn with(mut self, color_id: u8, material: Material) -> Self {
+        self.materials[color_id as usize] = material;
+        self
+    }
+
+    #[inline(always)]
+    pub fn get(&self, color_id: u8) -> &Material {
+        &self.materials[color_id as usize]
+    }
+
+    /// Light for an entity built from `model`, taken from the strongest glowing material in it
+    /// and tinted towards that material's palette entry
+    pub fn glow_light(&self, model: &VoxelModel) -> Option<PointLight> {
+        let mut finder = GlowFinder { materials: self, strongest: None };
+        model.traverse(&mut finder);
+        finder.strongest.map(|(color_id, glow)| PointLight {
+            radius: glow.radius,
+            intensity: glow.intensity,
+            color_ramp: color_id
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::voxel_model::VoxelModel;
+
+    use super::{Glow, Material, MaterialTable};
+
+    #[test]
+    fn test_glow_light() {
+        let dim = Glow { radius: 16.0, intensity: 0.25 };
+        let bright = Glow { radius: 64.0, intensity: 0.75 };
+        let materials = MaterialTable::default()
+            .with(3, Material { emissive: true, glow: Some(dim), translucent: false, reflective: false })
+            .with(5, Material { emissive: true, glow: Some(bright), translucent: false, reflective: false });
+
+        assert!(materials.get(3).emissive && !materials.get(4).emissive);
+        assert!(materials.glow_light(&VoxelModel::make_sphere32x32x32(0, 4)).is_none());
+
+        let light = materials.glow_light(&VoxelModel::make_sphere32x32x32(3, 5)).unwrap();
+        assert_eq!((light.radius, light.intensity, light.color_ramp), (64.0, 0.75, 5));
+    }
+}
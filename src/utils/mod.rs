@@ -1,3 +1,9 @@
 pub mod rendering;
 pub mod ray_queries;
+pub mod ray_packets;
+pub mod bvh;
+pub mod shape_queries;
 pub mod loaders;
+pub mod palette;
+pub mod materials;
+pub mod image_writers;
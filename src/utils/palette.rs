@@ -0,0 +1,197 @@
+/// Number of brightness levels in a [`LightTable`], the last one being full brightness
+pub const LIGHT_LEVELS: usize = 16;
+
+/// Palette entries whose colors rotate through `indices`, moving `speed` steps per second
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaletteCycle {
+    pub name: String,
+    pub speed: f32,
+    pub indices: Vec<u8>
+}
+
+/// Entries rotated by any of `cycles`, sorted. Colors the game computes or picks on its own must
+/// keep clear of them: a shaded pixel or a HUD color landing on one would flash along with the
+/// cycle. Tables built from the palette take them as their `excluded` entries.
+pub fn cycled_indices(cycles: &[PaletteCycle]) -> Vec<u8> {
+    let mut indices: Vec<u8> = cycles.iter().flat_map(|cycle| cycle.indices.iter().copied()).collect();
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
+
+/// Panics when one of the flat `color_ids` drawn by `what` is among the [`cycled_indices`]
+pub fn assert_not_cycled(what: &str, color_ids: &[u8], cycled: &[u8]) {
+    let clashing: Vec<u8> = color_ids.iter().copied().filter(|color_id| cycled.contains(color_id)).collect();
+    assert!(clashing.is_empty(), "{} uses palette entries {:?}, which palette cycles animate", what, clashing);
+}
+
+/// Index of the palette entry closest to `rgb`, skipping the `excluded` entries. Ties go to the lower index.
+pub fn nearest_color(palette: &[[u8; 3]], excluded: &[u8], rgb: [f32; 3]) -> u8 {
+    let distance = |[r, g, b]: [u8; 3]| {
+        let (dr, dg, db) = (r as f32 - rgb[0], g as f32 - rgb[1], b as f32 - rgb[2]);
+        dr * dr + dg * dg + db * db
+    };
+
+    let mut best = (0, f32::INFINITY);
+    for (ix, &color) in palette.iter().enumerate().take(256) {
+        if excluded.contains(&(ix as u8)) { continue; }
+        let d = distance(color);
+        if d < best.1 { best = (ix, d); }
+    }
+    best.0 as u8
+}
+
+/// Palette lookup table whose rows fade every entry step by step towards a target color.
+///
+/// Row 0 leaves colors as they are and the last row maps everything to the entry closest to the
+/// target, with rows in between picking the nearest palette entry to the blended color. Only
+/// row 0 may map to one of the `excluded` entries, and only when it is given one.
+pub struct Colormap {
+    rows: Vec<[u8; 256]>
+}
+
+impl Colormap {
+    pub fn fade(palette: &[[u8; 3]], excluded: &[u8], target: [u8; 3], rows: usize) -> Self {
+        let rows = (0..rows)
+            .map(|row| {
+                let amount = row as f32 / (rows.max(2) - 1) as f32;
+                let mut colors = [0; 256];
+                for (ix, clr) in colors.iter_mut().enumerate() {
+                    // entries missing from the palette are left as they are
+                    *clr = match palette.get(ix) {
+                        Some(_) if row == 0 => ix as u8,
+                        Some(rgb) => {
+                            let blended = [0, 1, 2].map(|c| rgb[c] as f32 + (target[c] as f32 - rgb[c] as f32) * amount);
+                            nearest_color(palette, excluded, blended)
+                        },
+                        None => ix as u8
+                    };
+                }
+                colors
+            })
+            .collect();
+        Self { rows }
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.rows.len()
+    }
+
+    #[inline(always)]
+    pub fn get(&self, color_id: u8, row: usize) -> u8 {
+        self.rows[row.min(self.rows.len() - 1)][color_id as usize]
+    }
+}
+
+/// Maps `(color_id, level)` to the palette entry best matching that color scaled to the
+/// brightness of the level, so shading keeps the output within the 8-bit palette.
+pub struct LightTable {
+    colormap: Colormap
+}
+
+impl LightTable {
+    pub fn new(palette: &[[u8; 3]], excluded: &[u8]) -> Self {
+        Self { colormap: Colormap::fade(palette, excluded, [0, 0, 0], LIGHT_LEVELS) }
+    }
+
+    #[inline(always)]
+    pub fn get(&self, color_id: u8, level: usize) -> u8 {
+        self.colormap.get(color_id, LIGHT_LEVELS - 1 - level.min(LIGHT_LEVELS - 1))
+    }
+}
+
+/// Maps a `(front, back)` pair of palette entries to the entry closest to `front` laid over
+/// `back` with the given opacity, which is never one of the `excluded` entries
+pub struct BlendTable {
+    palette_len: usize,
+    colors: Vec<u8>
+}
+
+impl BlendTable {
+    pub fn new(palette: &[[u8; 3]], excluded: &[u8], opacity: f32) -> Self {
+        let palette_len = palette.len().min(256);
+        let mut colors = Vec::with_capacity(palette_len * palette_len);
+        for front in &palette[..palette_len] {
+            for back in &palette[..palette_len] {
+                let blended = [0, 1, 2].map(|c| back[c] as f32 + (front[c] as f32 - back[c] as f32) * opacity);
+                colors.push(nearest_color(palette, excluded, blended));
+            }
+        }
+        Self { palette_len, colors }
+    }
+
+    /// Entries missing from the palette are not blended, the front one is kept as it is
+    #[inline(always)]
+    pub fn get(&self, front: u8, back: u8) -> u8 {
+        let (front_ix, back_ix) = (front as usize, back as usize);
+        if front_ix >= self.palette_len || back_ix >= self.palette_len { return front; }
+        self.colors[front_ix * self.palette_len + back_ix]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cycled_indices, nearest_color, BlendTable, Colormap, LightTable, PaletteCycle, LIGHT_LEVELS};
+
+    #[test]
+    fn test_light_table() {
+        let palette = [[0, 0, 0], [40, 40, 40], [200, 40, 40], [100, 20, 20], [40, 200, 40], [200, 200, 200]];
+        assert_eq!(nearest_color(&palette, &[], [190.0, 50.0, 30.0]), 2);
+        assert_eq!(nearest_color(&palette, &[], [20.0, 20.0, 20.0]), 0);
+
+        let table = LightTable::new(&palette, &[]);
+        for color_id in 0..palette.len() as u8 {
+            assert_eq!(table.get(color_id, LIGHT_LEVELS - 1), color_id);
+            assert_eq!(table.get(color_id, 0), 0);
+        }
+        // half bright red lands on the dark red entry
+        assert_eq!(table.get(2, LIGHT_LEVELS / 2), 3);
+        assert_eq!(table.get(100, 3), 100);
+
+        let fog = Colormap::fade(&palette, &[], [200, 200, 200], 8);
+        assert_eq!(fog.row_count(), 8);
+        for color_id in 0..palette.len() as u8 {
+            assert_eq!(fog.get(color_id, 0), color_id);
+            assert_eq!(fog.get(color_id, 7), 5);
+        }
+        assert_eq!(fog.get(0, 2), 1);
+
+        let blend = BlendTable::new(&palette, &[], 0.5);
+        assert_eq!(blend.get(2, 2), 2);
+        // red over black halves it
+        assert_eq!(blend.get(2, 0), 3);
+        assert_eq!(blend.get(0, 5), 1);
+        assert_eq!(blend.get(200, 5), 200);
+    }
+
+    #[test]
+    fn test_cycled_entries_excluded() {
+        let palette: Vec<[u8; 3]> = (0..32u8).map(|i| [i * 8, 255 - i * 8, (i % 4) * 60]).collect();
+        let cycles = [
+            PaletteCycle { name: "lava".to_string(), speed: 3.0, indices: vec![27, 5, 8] },
+            PaletteCycle { name: "water".to_string(), speed: -2.0, indices: vec![17, 18, 19, 5] }
+        ];
+        let cycled = cycled_indices(&cycles);
+        assert_eq!(cycled, vec![5, 8, 17, 18, 19, 27]);
+        // colors matching a cycled entry exactly still go elsewhere
+        assert!(!cycled.contains(&nearest_color(&palette, &cycled, [40.0, 215.0, 60.0])));
+
+        let light_table = LightTable::new(&palette, &cycled);
+        let fade = Colormap::fade(&palette, &cycled, [200, 40, 90], 8);
+        let blend = BlendTable::new(&palette, &cycled, 0.5);
+        for color_id in 0..palette.len() as u8 {
+            // cycled entries only stay themselves while unshaded
+            assert_eq!(light_table.get(color_id, LIGHT_LEVELS - 1), color_id);
+            assert_eq!(fade.get(color_id, 0), color_id);
+            for level in 0..LIGHT_LEVELS - 1 {
+                assert!(!cycled.contains(&light_table.get(color_id, level)), "{} at level {}", color_id, level);
+            }
+            for row in 1..fade.row_count() {
+                assert!(!cycled.contains(&fade.get(color_id, row)), "{} in row {}", color_id, row);
+            }
+            for back in 0..palette.len() as u8 {
+                assert!(!cycled.contains(&blend.get(color_id, back)), "{} over {}", color_id, back);
+            }
+        }
+    }
+}
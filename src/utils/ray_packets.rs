@@ -0,0 +1,163 @@
+use glam::{vec3a, BVec4A, Vec3A, Vec4};
+
+use crate::{
+    utils::ray_queries::{entry_face, Face},
+    voxel_model::{VoxelData, VoxelDataVisitor}
+};
+
+pub const PACKET_WIDTH: usize = 4;
+
+/// Four rays stored in structure-of-arrays layout, one ray per lane.
+#[derive(Clone, Copy, Debug)]
+pub struct RayPacket4 {
+    pub origin: [Vec4; 3],
+    pub dir: [Vec4; 3]
+}
+
+impl RayPacket4 {
+    pub fn from_rays(rays: [(Vec3A, Vec3A); PACKET_WIDTH]) -> Self {
+        let lanes = |f: fn(&(Vec3A, Vec3A)) -> f32| Vec4::new(f(&rays[0]), f(&rays[1]), f(&rays[2]), f(&rays[3]));
+        Self {
+            origin: [lanes(|r| r.0.x), lanes(|r| r.0.y), lanes(|r| r.0.z)],
+            dir: [lanes(|r| r.1.x), lanes(|r| r.1.y), lanes(|r| r.1.z)]
+        }
+    }
+
+    pub fn ray(&self, lane: usize) -> (Vec3A, Vec3A) {
+        (
+            vec3a(self.origin[0][lane], self.origin[1][lane], self.origin[2][lane]),
+            vec3a(self.dir[0][lane], self.dir[1][lane], self.dir[2][lane])
+        )
+    }
+}
+
+/// Packet counterpart of [`cast_ray_to_box`](super::ray_queries::cast_ray_to_box).
+///
+/// Returns the mask of lanes touching the box together with their entry and exit `t`.
+/// Values of lanes outside the mask are unspecified. For lanes inside it the results are
+/// identical to the scalar version, so both paths can be mixed freely.
+#[inline(always)]
+pub fn cast_ray_packet_to_box(
+    packet: &RayPacket4,
+    p0: Vec3A,
+    size: Vec3A
+) -> (BVec4A, Vec4, Vec4) {
+    let p1 = p0 + size;
+
+    let mut t_enter = Vec4::ZERO;
+    let mut t_exit = Vec4::splat(f32::INFINITY);
+    let mut hit = BVec4A::new(true, true, true, true);
+
+    for axis in 0..3 {
+        let (origin, dir) = (packet.origin[axis], packet.dir[axis]);
+        let (min, max) = (Vec4::splat(p0[axis]), Vec4::splat(p1[axis]));
+
+        let inv_dir = Vec4::ONE / dir;
+        let t0 = (min - origin) * inv_dir;
+        let t1 = (max - origin) * inv_dir;
+        let (t0, t1) = (t0.min(t1), t0.max(t1));
+
+        // lanes parallel to the slab either stay inside it forever or never touch it
+        let parallel = dir.cmpeq(Vec4::ZERO);
+        let inside_slab = origin.cmpge(min) & origin.cmple(max);
+        hit &= !parallel | inside_slab;
+
+        t_enter = Vec4::select(parallel, t_enter, t_enter.max(t0));
+        t_exit = Vec4::select(parallel, t_exit, t_exit.min(t1));
+    }
+
+    (hit & t_enter.cmple(t_exit), t_enter, t_exit)
+}
+
+/// Packet counterpart of [`VoxelIntersector`](super::ray_queries::VoxelIntersector).
+///
+/// `min_t` holds the closest hit per lane, `color_ids` its color, with color 0 meaning
+/// no hit yet, and `faces` the side of the voxel it entered through. Lanes should start
+/// at `f32::INFINITY`; starting a lane at `f32::NEG_INFINITY` disables it, which is how
+/// partially filled packets are traced.
+pub struct VoxelPacketIntersector<'a> {
+    pub packet: &'a RayPacket4,
+    pub pos: Vec3A,
+    pub min_t: &'a mut Vec4,
+    pub color_ids: &'a mut [u8; PACKET_WIDTH],
+    pub faces: &'a mut [Face; PACKET_WIDTH]
+}
+
+impl<'a> VoxelDataVisitor for VoxelPacketIntersector<'a> {
+    fn visit(
+        &mut self,
+        min_c: &[usize],
+        max_c: &[usize],
+        data: &VoxelData
+    ) -> bool {
+        let p0 = vec3a(min_c[0] as f32, min_c[1] as f32, min_c[2] as f32);
+        let size = vec3a(max_c[0] as f32, max_c[1] as f32, max_c[2] as f32) - p0;
+
+        let (hit, t_enter, _) = cast_ray_packet_to_box(self.packet, self.pos + p0, size);
+        let closer = hit & t_enter.cmplt(*self.min_t);
+
+        match data {
+            VoxelData::Node2x2x2 { .. } => closer.any(),
+            VoxelData::Leaf { color_id } if *color_id == 0 => false,
+            &VoxelData::Leaf { color_id } => {
+                *self.min_t = Vec4::select(closer, t_enter, *self.min_t);
+                let mask = closer.bitmask();
+                for lane in 0..PACKET_WIDTH {
+                    if mask & (1 << lane) == 0 { continue; }
+                    let (ray_origin, ray_dir) = self.packet.ray(lane);
+                    self.color_ids[lane] = color_id;
+                    self.faces[lane] = entry_face(ray_origin, ray_dir, self.pos + p0, size);
+                }
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use glam::{vec3a, Vec4};
+
+    use crate::{utils::ray_queries::{Face, VoxelIntersector}, voxel_model::VoxelModel};
+
+    use super::{RayPacket4, VoxelPacketIntersector};
+
+    #[test]
+    fn test_packet_matches_scalar() {
+        let sphere = VoxelModel::make_sphere32x32x32(0, 5);
+        let pos = vec3a(-16.0, -16.0, 40.0);
+
+        for j in 0..32 {
+            for i in (0..32).step_by(4) {
+                let rays = [0, 1, 2, 3].map(|lane| {
+                    let origin = vec3a(0.0, 0.0, (i + lane) as f32 - 16.0);
+                    let dir = vec3a((i + lane) as f32 - 16.0, j as f32 - 16.0, 40.0).normalize();
+                    (origin, dir)
+                });
+
+                let packet = RayPacket4::from_rays(rays);
+                let (mut min_t, mut color_ids, mut faces) = (Vec4::splat(f32::INFINITY), [0; 4], [Face::Inside; 4]);
+                sphere.traverse(&mut VoxelPacketIntersector {
+                    packet: &packet,
+                    pos,
+                    min_t: &mut min_t,
+                    color_ids: &mut color_ids,
+                    faces: &mut faces
+                });
+
+                for (lane, (ray_origin, ray_dir)) in rays.into_iter().enumerate() {
+                    let mut min = None;
+                    sphere.traverse(&mut VoxelIntersector { ray_origin, ray_dir, pos, min: &mut min, see_through: None });
+                    match min {
+                        Some(hit) => {
+                            assert_eq!(hit.t, min_t[lane]);
+                            assert_eq!(hit.color_id, color_ids[lane]);
+                            assert_eq!(hit.normal, faces[lane].normal());
+                        },
+                        None => assert_eq!(color_ids[lane], 0)
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,69 +1,171 @@
-use core::f32;
+use edict::world::World;
+use glam::{vec3a, Quat, Vec3A};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
-use glam::{vec3a, Vec3A};
-
-use crate::voxel_model::{VoxelData, VoxelDataVisitor};
+use crate::{
+    components::{Position, Rotation, Scale, Voxel},
+    utils::{bvh::EntityBvh, materials::MaterialTable},
+    voxel_model::{VoxelData, VoxelDataVisitor}
+};
 
+/// Slab test of a ray against the closed box `[p0, p0 + size]`.
+///
+/// Returns `Some((t_enter, t_exit))` with `0.0 <= t_enter <= t_exit` when the ray touches the box.
+/// Boxes are treated as closed sets, so rays grazing a face or an edge still count as hits
+/// (with `t_enter == t_exit` for a single touching point). If `ray_origin` lies inside the box
+/// (faces included) `t_enter` is clamped to `0.0` and `t_exit` is where the ray leaves it.
+/// Zero direction components are handled explicitly instead of relying on infinities:
+/// such a ray hits only if its origin lies within the slab of that axis.
 #[inline(always)]
 pub fn cast_ray_to_box(
     ray_origin : Vec3A,
     ray_dir : Vec3A,
     p0 : Vec3A,
     size : Vec3A
-) -> Option<f32> {
+) -> Option<(f32, f32)> {
     let p1 = p0 + size;
 
-    if (p0.x ..= p1.x).contains(&ray_origin.x) {
-        if (p0.y ..= p1.y).contains(&ray_origin.y) {
-            if (p0.z ..= p1.z).contains(&ray_origin.z) {
-                return Some(0.0);
-            }
+    let mut t_enter = 0.0f32;
+    let mut t_exit = f32::INFINITY;
+
+    for axis in 0..3 {
+        let (origin, dir) = (ray_origin[axis], ray_dir[axis]);
+        let (min, max) = (p0[axis], p1[axis]);
+
+        if dir == 0.0 {
+            if origin < min || origin > max { return None; }
+            continue;
         }
-    }
 
-    let t012 = (p0 - ray_origin) / ray_dir;
-    let t345 = (p1 - ray_origin) / ray_dir;
+        let inv_dir = 1.0 / dir;
+        let (t0, t1) = ((min - origin) * inv_dir, (max - origin) * inv_dir);
+        let (t0, t1) = if t0 <= t1 { (t0, t1) } else { (t1, t0) };
 
-    #[inline(always)]
-    fn range(x: f32, y: f32) -> std::ops::RangeInclusive<f32> {
-        if x <= y { x..=y } else { y..=x }
+        t_enter = t_enter.max(t0);
+        t_exit = t_exit.min(t1);
+        if t_enter > t_exit { return None; }
     }
 
-    let (x_range, y_range, z_range) = (
-        &range(t012.x, t345.x),
-        &range(t012.y, t345.y),
-        &range(t012.z, t345.z)
-    );
+    Some((t_enter, t_exit))
+}
 
-    #[inline(always)]
-    fn get_min_t(
-        checked_r: &std::ops::RangeInclusive<f32>,
-        r0: &std::ops::RangeInclusive<f32>,
-        r1: &std::ops::RangeInclusive<f32>,
-        min_t: &mut Option<f32>
-    ) {
-        let t = *checked_r.start();
-        if t >= 0.0 && r0.contains(&t) && r1.contains(&t) {
-            *min_t = Some(min_t.map(|old_t| old_t.min(t)).unwrap_or(t));
+/// Side of a voxel box through which a ray entered it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Face {
+    NegX,
+    PosX,
+    NegY,
+    PosY,
+    NegZ,
+    PosZ,
+    /// the ray started inside the box
+    Inside
+}
+
+impl Face {
+    pub fn normal(self) -> Vec3A {
+        match self {
+            Face::NegX => -Vec3A::X,
+            Face::PosX => Vec3A::X,
+            Face::NegY => -Vec3A::Y,
+            Face::PosY => Vec3A::Y,
+            Face::NegZ => -Vec3A::Z,
+            Face::PosZ => Vec3A::Z,
+            Face::Inside => Vec3A::ZERO
         }
-        let t = *checked_r.end();
-        if t >= 0.0 && r0.contains(&t) && r1.contains(&t) {
-            *min_t = Some(min_t.map(|old_t| old_t.min(t)).unwrap_or(t));
+    }
+}
+
+/// Face of the box `[p0, p0 + size]` through which the ray enters it, assuming it hits the box
+#[inline(always)]
+pub fn entry_face(
+    ray_origin : Vec3A,
+    ray_dir : Vec3A,
+    p0 : Vec3A,
+    size : Vec3A
+) -> Face {
+    let p1 = p0 + size;
+
+    let mut face = Face::Inside;
+    let mut t_enter = 0.0;
+    for (axis, faces) in [(0, [Face::NegX, Face::PosX]), (1, [Face::NegY, Face::PosY]), (2, [Face::NegZ, Face::PosZ])] {
+        let dir = ray_dir[axis];
+        if dir == 0.0 { continue; }
+
+        // a ray moving towards +axis enters through the face looking towards -axis
+        let (bound, axis_face) = if dir > 0.0 { (p0[axis], faces[0]) } else { (p1[axis], faces[1]) };
+        let t = (bound - ray_origin[axis]) / dir;
+        if t > t_enter {
+            t_enter = t;
+            face = axis_face;
         }
     }
+    face
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayHit {
+    pub t: f32,
+    pub color_id: u8,
+    /// world space normal of the face the ray entered through, zero when it started inside a voxel
+    pub normal: Vec3A
+}
+
+/// Rotation of a model about its center followed by uniform scaling.
+///
+/// A model of `size` placed at `pos` spans `[pos, pos + size * scale]` before being rotated, so
+/// scaling grows it away from its `Position` while rotating turns it in place.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelTransform {
+    pub rotation: Quat,
+    pub scale: f32
+}
+
+impl ModelTransform {
+    /// Transform of an entity with optional `Rotation` and `Scale`, `None` when it has neither
+    pub fn from_components(rotation: Option<&Rotation>, scale: Option<&Scale>) -> Option<Self> {
+        if rotation.is_none() && scale.is_none() { return None; }
+        Some(Self {
+            rotation: rotation.map_or(Quat::IDENTITY, |rotation| rotation.value),
+            scale: scale.map_or(1.0, |scale| scale.value)
+        })
+    }
+
+    /// The ray in the space of the model, where it spans `[0, size]`. Distances along the ray are
+    /// kept, so hits found there need no conversion except for their normals.
+    #[inline(always)]
+    pub fn ray_to_model(&self, pos: Vec3A, size: Vec3A, ray_origin: Vec3A, ray_dir: Vec3A) -> (Vec3A, Vec3A) {
+        let center = size * 0.5;
+        let inverse = self.rotation.inverse();
+        let origin = inverse.mul_vec3a(ray_origin - pos - center * self.scale) / self.scale + center;
+        (origin, inverse.mul_vec3a(ray_dir) / self.scale)
+    }
+
+    #[inline(always)]
+    pub fn normal_to_world(&self, normal: Vec3A) -> Vec3A {
+        self.rotation.mul_vec3a(normal)
+    }
 
-    let min_t = &mut None;
-    if t012.x.is_finite() { get_min_t(x_range, y_range, z_range, min_t); }
-    if t012.y.is_finite() { get_min_t(y_range, x_range, z_range, min_t); }
-    if t012.z.is_finite() { get_min_t(z_range, x_range, y_range, min_t); }
-    *min_t
+    /// World space bounding box of a model of `size` placed at `pos`
+    pub fn bounds(&self, pos: Vec3A, size: Vec3A) -> (Vec3A, Vec3A) {
+        let center = pos + size * 0.5 * self.scale;
+        let half = size * 0.5 * self.scale;
+        let extent = (0..3).fold(Vec3A::ZERO, |extent, axis| {
+            let mut axis_half = Vec3A::ZERO;
+            axis_half[axis] = half[axis];
+            extent + self.rotation.mul_vec3a(axis_half).abs()
+        });
+        (center - extent, center + extent)
+    }
 }
 
 pub struct VoxelIntersector<'a> {
     pub ray_origin: Vec3A,
     pub ray_dir: Vec3A,
     pub pos: Vec3A,
-    pub min: &'a mut Option<(f32, u8)>
+    pub min: &'a mut Option<RayHit>,
+    /// when set, voxels of translucent materials are passed through like empty ones
+    pub see_through: Option<&'a MaterialTable>
 }
 impl<'a> VoxelDataVisitor for VoxelIntersector<'a> {
     fn visit(
@@ -80,39 +182,134 @@ impl<'a> VoxelDataVisitor for VoxelIntersector<'a> {
             self.ray_dir,
             self.pos + p0,
             size
-        );
+        ).map(|(t_enter, _)| t_enter);
 
         match data {
             VoxelData::Node2x2x2 { .. } => match (*self.min, intersection) {
-                (Some((old_t, _)), Some(t)) if t < old_t => true,
+                (Some(old), Some(t)) if t < old.t => true,
                 (None, Some(_)) => true,
                 _ => false
             },
             VoxelData::Leaf { color_id } if *color_id == 0 => false,
+            VoxelData::Leaf { color_id } if self.see_through.is_some_and(|m| m.get(*color_id).translucent) => false,
             &VoxelData::Leaf { color_id } => {
-                match (*self.min, intersection) {
-                    (Some((old_t, _)), Some(t)) if t < old_t => {
-                        assert!(!old_t.is_nan());
+                let t = match (*self.min, intersection) {
+                    (Some(old), Some(t)) if t < old.t => {
+                        assert!(!old.t.is_nan());
                         assert!(!t.is_nan());
-                        *self.min = Some((t, color_id))
+                        t
                     },
                     (None, Some(t)) => {
                         assert!(!t.is_nan());
-                        *self.min = Some((t, color_id))
+                        t
                     },
-                    _ => ()
-                }
+                    _ => return false
+                };
+                let normal = entry_face(self.ray_origin, self.ray_dir, self.pos + p0, size).normal();
+                *self.min = Some(RayHit { t, color_id, normal });
+                false
+            }
+        }
+    }
+}
+
+/// Any-hit counterpart of [`VoxelIntersector`]: stops at the first solid voxel entered
+/// before `max_t`, whether it is the closest one or not.
+pub struct VoxelOccluder {
+    pub ray_origin: Vec3A,
+    pub ray_dir: Vec3A,
+    pub pos: Vec3A,
+    pub max_t: f32,
+    pub occluded: bool
+}
+impl VoxelDataVisitor for VoxelOccluder {
+    fn visit(
+        &mut self,
+        min_c: &[usize],
+        max_c: &[usize],
+        data: &VoxelData
+    ) -> bool {
+        if self.occluded { return false; }
+
+        let p0 = vec3a(min_c[0] as f32, min_c[1] as f32, min_c[2] as f32);
+        let size = vec3a(max_c[0] as f32, max_c[1] as f32, max_c[2] as f32) - p0;
+
+        match cast_ray_to_box(self.ray_origin, self.ray_dir, self.pos + p0, size) {
+            Some((t_enter, _)) if t_enter < self.max_t => (),
+            _ => return false
+        }
+
+        match data {
+            VoxelData::Node2x2x2 { .. } => true,
+            VoxelData::Leaf { color_id } if *color_id == 0 => false,
+            VoxelData::Leaf { .. } => {
+                self.occluded = true;
                 false
             }
         }
     }
 }
 
+/// Splits the segment `a..b` into a ray and its length, `None` for degenerate segments
+#[inline(always)]
+fn segment_to_ray(a: Vec3A, b: Vec3A) -> Option<(Vec3A, Vec3A, f32)> {
+    let dist = a.distance(b);
+    (dist > 0.0).then(|| (a, (b - a) / dist, dist))
+}
+
+/// Checks whether the segment from `a` to `b` is free of solid voxels.
+///
+/// A voxel which `b` merely lies on (like a target standing on the floor) does not block the view.
+pub fn line_of_sight(world: &World, a: Vec3A, b: Vec3A) -> bool {
+    let Some((ray_origin, ray_dir, max_t)) = segment_to_ray(a, b) else { return true; };
+
+    for (pos, vox, rotation, scale) in world.view::<(&Position, &Voxel, Option<&Rotation>, Option<&Scale>)>() {
+        let mut occluder = match ModelTransform::from_components(rotation, scale) {
+            Some(transform) => {
+                let [w, h, d] = vox.data.size;
+                let size = vec3a(w as f32, h as f32, d as f32);
+                let (ray_origin, ray_dir) = transform.ray_to_model(pos.value, size, ray_origin, ray_dir);
+                VoxelOccluder { ray_origin, ray_dir, pos: Vec3A::ZERO, max_t, occluded: false }
+            },
+            None => VoxelOccluder { ray_origin, ray_dir, pos: pos.value, max_t, occluded: false }
+        };
+        vox.data.traverse(&mut occluder);
+        if occluder.occluded { return false; }
+    }
+    true
+}
+
+/// Batched [`line_of_sight`] for many `(a, b)` pairs per frame, checked in parallel
+/// against a single [`EntityBvh`] built for the whole batch.
+pub fn line_of_sight_batch(world: &World, pairs: &[(Vec3A, Vec3A)]) -> Vec<bool> {
+    let bvh = EntityBvh::from_world(world);
+    pairs
+        .par_iter()
+        .map(|&(a, b)| match segment_to_ray(a, b) {
+            Some((ray_origin, ray_dir, max_t)) => !bvh.is_occluded(ray_origin, ray_dir, max_t),
+            None => true
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
-    use glam::vec3a;
+    use edict::world::World;
+    use glam::{vec3a, Vec3A};
+
+    use crate::{components::{Position, Voxel}, voxel_model::VoxelModel};
 
-    use super::cast_ray_to_box;
+    use super::{cast_ray_to_box, entry_face, line_of_sight, line_of_sight_batch, Face};
+
+    const EPS: f32 = 1e-4;
+
+    fn assert_hit(hit: Option<(f32, f32)>, t_enter: f32, t_exit: f32) {
+        let Some((actual_enter, actual_exit)) = hit else {
+            panic!("expected hit at ({}, {}), got None", t_enter, t_exit);
+        };
+        assert!((actual_enter - t_enter).abs() < EPS, "t_enter: {} != {}", actual_enter, t_enter);
+        assert!((actual_exit - t_exit).abs() < EPS, "t_exit: {} != {}", actual_exit, t_exit);
+    }
 
     #[test]
     fn test_cast_ray_to_box() {
@@ -122,35 +319,211 @@ mod test {
         let p1 = vec3a(-16.0, -48.0, 64.0);
         let size_1 = vec3a(32.0, 32.0, 32.0);
 
-        let ray_origin = vec3a(0.0, -24.0, 70.0);
         let ray_dir = vec3a(0.0052223853, -0.55721146, 0.8303543);
 
-        let t0 = cast_ray_to_box(ray_origin, ray_dir, p0, size_0);
-        let t1 = cast_ray_to_box(ray_origin, ray_dir, p1, size_1);
+        for z in [70.0, 72.0, 80.0] {
+            let ray_origin = vec3a(0.0, -24.0, z);
+
+            let t_front = (96.0 - ray_origin.z) / ray_dir.z;
+            let t_bottom = (-48.0 - ray_origin.y) / ray_dir.y;
+
+            // the origin is inside the near box, so entry is clamped to zero
+            assert_hit(cast_ray_to_box(ray_origin, ray_dir, p1, size_1), 0.0, t_front);
 
-        //assert!(t0.is_some());
-        //assert!(t1.is_some());
+            // the ray passes straight into the far box and leaves it through the bottom face
+            assert_hit(cast_ray_to_box(ray_origin, ray_dir, p0, size_0), t_front, t_bottom);
+        }
+    }
+
+    #[test]
+    fn test_cast_ray_to_box_axis_parallel() {
+        let (p0, size) = (vec3a(0.0, 0.0, 0.0), vec3a(4.0, 4.0, 4.0));
 
-        println!("{:?}, {:?}", t0, t1);
+        assert_hit(cast_ray_to_box(vec3a(2.0, 2.0, -3.0), Vec3A::Z, p0, size), 3.0, 7.0);
+        assert_hit(cast_ray_to_box(vec3a(2.0, 2.0, 10.0), -Vec3A::Z, p0, size), 6.0, 10.0);
+        assert_hit(cast_ray_to_box(vec3a(-1.0, 2.0, 2.0), Vec3A::X, p0, size), 1.0, 5.0);
+        assert_hit(cast_ray_to_box(vec3a(2.0, 7.0, 2.0), -Vec3A::Y, p0, size), 3.0, 7.0);
+
+        assert_eq!(cast_ray_to_box(vec3a(5.0, 2.0, -3.0), Vec3A::Z, p0, size), None);
+        assert_eq!(cast_ray_to_box(vec3a(2.0, -0.5, -3.0), Vec3A::Z, p0, size), None);
+        assert_eq!(cast_ray_to_box(vec3a(2.0, 2.0, -3.0), -Vec3A::Z, p0, size), None);
+    }
+
+    #[test]
+    fn test_cast_ray_to_box_faces_and_edges() {
+        let (p0, size) = (vec3a(0.0, 0.0, 0.0), vec3a(4.0, 4.0, 4.0));
 
-        let ray_origin = vec3a(0.0, -24.0, 72.0);
+        // sliding along a face
+        assert_hit(cast_ray_to_box(vec3a(0.0, 2.0, -3.0), Vec3A::Z, p0, size), 3.0, 7.0);
+        assert_hit(cast_ray_to_box(vec3a(4.0, 2.0, -3.0), Vec3A::Z, p0, size), 3.0, 7.0);
 
-        let t0 = cast_ray_to_box(ray_origin, ray_dir, p0, size_0);
-        let t1 = cast_ray_to_box(ray_origin, ray_dir, p1, size_1);
+        // sliding along an edge
+        assert_hit(cast_ray_to_box(vec3a(0.0, 4.0, -3.0), Vec3A::Z, p0, size), 3.0, 7.0);
 
-        //assert!(t0.is_some());
-        //assert!(t1.is_some());
+        // entering and leaving exactly through opposite edges
+        let hit = cast_ray_to_box(vec3a(-2.0, -2.0, 2.0), vec3a(1.0, 1.0, 0.0).normalize(), p0, size);
+        assert_hit(hit, 8.0f32.sqrt(), 72.0f32.sqrt());
 
-        println!("{:?}, {:?}", t0, t1);
+        // touching a single edge diagonally
+        let hit = cast_ray_to_box(vec3a(-2.0, 2.0, 2.0), vec3a(1.0, 1.0, 0.0).normalize(), p0, size);
+        assert_hit(hit, 8.0f32.sqrt(), 8.0f32.sqrt());
 
-        let ray_origin = vec3a(0.0, -24.0, 80.0);
+        // origin on a face looking away
+        assert_hit(cast_ray_to_box(vec3a(4.0, 2.0, 2.0), Vec3A::X, p0, size), 0.0, 0.0);
+    }
+
+    #[test]
+    fn test_cast_ray_to_box_origin_inside() {
+        let (p0, size) = (vec3a(0.0, 0.0, 0.0), vec3a(4.0, 4.0, 4.0));
+        assert_hit(cast_ray_to_box(vec3a(1.0, 2.0, 3.0), Vec3A::X, p0, size), 0.0, 3.0);
+        assert_hit(cast_ray_to_box(vec3a(1.0, 2.0, 3.0), -Vec3A::Z, p0, size), 0.0, 3.0);
+        assert_hit(cast_ray_to_box(vec3a(2.0, 2.0, 2.0), vec3a(1.0, 1.0, 1.0).normalize(), p0, size), 0.0, 12.0f32.sqrt());
+    }
+
+    #[test]
+    fn test_entry_face() {
+        let (p0, size) = (vec3a(0.0, 0.0, 0.0), vec3a(4.0, 4.0, 4.0));
+        assert_eq!(entry_face(vec3a(-2.0, 1.0, 1.0), Vec3A::X, p0, size), Face::NegX);
+        assert_eq!(entry_face(vec3a(1.0, 6.0, 1.0), -Vec3A::Y, p0, size), Face::PosY);
+        // closer to the top than to the side, so the ray comes down through the top
+        assert_eq!(entry_face(vec3a(-1.0, 7.0, 2.0), vec3a(1.0, -2.0, 0.0).normalize(), p0, size), Face::PosY);
+        assert_eq!(entry_face(vec3a(-3.0, 5.0, 2.0), vec3a(2.0, -1.0, 0.0).normalize(), p0, size), Face::NegX);
+        assert_eq!(entry_face(vec3a(1.0, 2.0, 3.0), Vec3A::Z, p0, size), Face::Inside);
+        assert_eq!(Face::PosZ.normal(), Vec3A::Z);
+    }
 
-        let t0 = cast_ray_to_box(ray_origin, ray_dir, p0, size_0);
-        let t1 = cast_ray_to_box(ray_origin, ray_dir, p1, size_1);
+    /// xorshift generator, so fuzzing stays deterministic and dependency free
+    struct Rng(u64);
+
+    impl Rng {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            let unit = (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32;
+            min + (max - min) * unit
+        }
+
+        fn int_range(&mut self, min: i32, max: i32) -> f32 {
+            (min + (self.next_u64() % (max - min + 1) as u64) as i32) as f32
+        }
+    }
+
+    /// Brute force reference: collects every point where the ray crosses one of the six face planes
+    /// (computed in f64) and keeps those lying on the box grown by `margin` on every side.
+    fn reference(ray_origin: Vec3A, ray_dir: Vec3A, p0: Vec3A, size: Vec3A, margin: f64) -> Option<(f64, f64)> {
+        const EPS: f64 = 1e-9;
+        let o = [ray_origin.x as f64, ray_origin.y as f64, ray_origin.z as f64];
+        let d = [ray_dir.x as f64, ray_dir.y as f64, ray_dir.z as f64];
+        let min = [p0.x as f64 - margin, p0.y as f64 - margin, p0.z as f64 - margin];
+        let max = [
+            p0.x as f64 + size.x as f64 + margin,
+            p0.y as f64 + size.y as f64 + margin,
+            p0.z as f64 + size.z as f64 + margin
+        ];
+
+        let inside = |t: f64| (0..3).all(|axis| {
+            let p = o[axis] + d[axis] * t;
+            p >= min[axis] - EPS && p <= max[axis] + EPS
+        });
+
+        let mut candidates = vec![0.0];
+        for axis in 0..3 {
+            if d[axis] == 0.0 { continue; }
+            for plane in [min[axis], max[axis]] {
+                let t = (plane - o[axis]) / d[axis];
+                if t >= 0.0 { candidates.push(t); }
+            }
+        }
+
+        let hits: Vec<f64> = candidates.into_iter().filter(|&t| inside(t)).collect();
+        let t_enter = hits.iter().cloned().reduce(f64::min)?;
+        let t_exit = hits.iter().cloned().reduce(f64::max)?;
+        Some((t_enter, t_exit))
+    }
+
+    /// Near-grazing rays are ambiguous under rounding, so unless `exact_inputs` is set only rays that
+    /// clearly miss or clearly pierce the box are checked.
+    fn check_against_reference(ray_origin: Vec3A, ray_dir: Vec3A, p0: Vec3A, size: Vec3A, exact_inputs: bool) {
+        let actual = cast_ray_to_box(ray_origin, ray_dir, p0, size);
+        let exact = reference(ray_origin, ray_dir, p0, size, 0.0);
+        let (strict, lenient) = if exact_inputs {
+            (exact, exact)
+        } else {
+            (reference(ray_origin, ray_dir, p0, size, -1e-3), reference(ray_origin, ray_dir, p0, size, 1e-3))
+        };
+        let context = format!("origin {:?}, dir {:?}, p0 {:?}, size {:?}", ray_origin, ray_dir, p0, size);
+
+        if lenient.is_none() {
+            assert_eq!(actual, None, "{}", context);
+        }
+        if let (Some(_), Some((ref_enter, ref_exit))) = (strict, exact) {
+            let Some((t_enter, t_exit)) = actual else { panic!("expected hit, {}", context); };
+            assert!(t_enter <= t_exit, "{}", context);
+            assert!((t_enter as f64 - ref_enter).abs() < 1e-2, "t_enter {} vs {}, {}", t_enter, ref_enter, context);
+            assert!((t_exit as f64 - ref_exit).abs() < 1e-2, "t_exit {} vs {}, {}", t_exit, ref_exit, context);
+        }
+    }
+
+    #[test]
+    fn fuzz_cast_ray_to_box_random_rays() {
+        let mut rng = Rng(0x9E3779B97F4A7C15);
+        for _ in 0..100_000 {
+            let p0 = vec3a(rng.range(-64.0, 64.0), rng.range(-64.0, 64.0), rng.range(-64.0, 64.0));
+            let size = vec3a(rng.range(0.5, 32.0), rng.range(0.5, 32.0), rng.range(0.5, 32.0));
+            let ray_origin = vec3a(rng.range(-128.0, 128.0), rng.range(-128.0, 128.0), rng.range(-128.0, 128.0));
+            let target = p0 + size * vec3a(rng.range(-0.5, 1.5), rng.range(-0.5, 1.5), rng.range(-0.5, 1.5));
+            let ray_dir = (target - ray_origin).normalize();
+            if !ray_dir.is_finite() { continue; }
+            check_against_reference(ray_origin, ray_dir, p0, size, false);
+        }
+    }
+
+    #[test]
+    fn fuzz_cast_ray_to_box_degenerate_rays() {
+        // integer lattice origins and axis aligned or diagonal directions land exactly on faces and edges
+        let mut rng = Rng(0xD1B54A32D192ED03);
+        let directions = [-1.0, 0.0, 1.0];
+        for _ in 0..100_000 {
+            let p0 = vec3a(rng.int_range(-4, 4), rng.int_range(-4, 4), rng.int_range(-4, 4));
+            let size = vec3a(rng.int_range(1, 4), rng.int_range(1, 4), rng.int_range(1, 4));
+            let ray_origin = vec3a(rng.int_range(-8, 8), rng.int_range(-8, 8), rng.int_range(-8, 8));
+            let ray_dir = Vec3A::from([0, 1, 2].map(|_| directions[(rng.next_u64() % 3) as usize]));
+            if ray_dir == Vec3A::ZERO { continue; }
+            check_against_reference(ray_origin, ray_dir.normalize(), p0, size, true);
+        }
+    }
+
+    #[test]
+    fn test_line_of_sight() {
+        let mut world = World::new();
+        world.spawn((Position { value: Vec3A::ZERO }, Voxel { data: VoxelModel::make_sphere32x32x32(0, 5) }));
+        world.spawn((Position { value: vec3a(64.0, 0.0, 0.0) }, Voxel { data: VoxelModel::make_sphere32x32x32(0, 7) }));
 
-        //assert!(t0.is_some());
-        //assert!(t1.is_some());
+        assert!(!line_of_sight(&world, vec3a(-10.0, 16.0, 16.0), vec3a(42.0, 16.0, 16.0)));
+        assert!(!line_of_sight(&world, vec3a(48.0, 16.0, 16.0), vec3a(110.0, 16.0, 16.0)));
+        assert!(line_of_sight(&world, vec3a(-10.0, 40.0, 16.0), vec3a(110.0, 40.0, 16.0)));
+        assert!(line_of_sight(&world, vec3a(40.0, 16.0, 16.0), vec3a(56.0, 16.0, -8.0)));
+        // the empty corner inside the model bounds
+        assert!(line_of_sight(&world, vec3a(0.5, 0.5, 0.5), vec3a(0.5, 0.5, 3.0)));
+        // standing right on top of the sphere
+        assert!(line_of_sight(&world, vec3a(15.5, 50.0, 15.5), vec3a(15.5, 31.0, 15.5)));
+        assert!(line_of_sight(&world, vec3a(15.5, 20.0, 15.5), vec3a(15.5, 20.0, 15.5)));
 
-        println!("{:?}, {:?}", t0, t1);
+        let pairs: Vec<(Vec3A, Vec3A)> = (0..256)
+            .map(|i| {
+                let a = vec3a((i % 16) as f32 * 8.0 - 20.0, -8.0, (i / 16) as f32 * 3.0 - 8.0);
+                let b = vec3a(100.0 - (i % 7) as f32 * 20.0, 40.0, (i % 5) as f32 * 10.0);
+                (a, b)
+            })
+            .collect();
+        let batch = line_of_sight_batch(&world, &pairs);
+        let single: Vec<bool> = pairs.iter().map(|&(a, b)| line_of_sight(&world, a, b)).collect();
+        assert_eq!(batch, single);
+        assert!(batch.iter().any(|&visible| visible) && batch.iter().any(|&visible| !visible));
     }
 }
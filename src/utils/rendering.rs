@@ -1,4 +1,4 @@
-use glam::{vec3a, Vec3A};
+use glam::{vec3a, Mat3A, Quat, Vec3A};
 
 pub const PIXELS_PER_METER: f32 = 512.0;
 pub const VIEW_RANGE: f32 = 14.0;
@@ -6,7 +6,7 @@ pub const VIEW_RANGE: f32 = 14.0;
 pub const NEAR: f32 = 0.005 * PIXELS_PER_METER;
 pub const FAR: f32 = PIXELS_PER_METER * VIEW_RANGE;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct FrustumPlane {
     pub top_left: Vec3A,
     pub top_right: Vec3A,
@@ -14,6 +14,29 @@ pub struct FrustumPlane {
     pub bottom_right: Vec3A,
 }
 
+/// Rectangle of the framebuffer a view is rendered into
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Viewport {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+impl Viewport {
+    pub fn full_screen(width: usize, height: usize) -> Self {
+        Self { x: 0, y: 0, width, height }
+    }
+
+    pub fn fits_into(&self, width: usize, height: usize) -> bool {
+        self.x + self.width <= width && self.y + self.height <= height
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width as f32 / self.height.max(1) as f32
+    }
+}
+
 #[inline(always)]
 fn rotate(p: (f32, f32), angle: f32) -> (f32, f32) {
     let sin_cos = (angle.sin(), angle.cos());
@@ -33,20 +56,126 @@ pub fn gen_trapezoid_coords(x: f32, y: f32, angle: f32, fov_slope: f32) -> [(f32
     ].map(|p| (p.0 + x as f32, y as f32 + p.1))
 }
 
-pub fn gen_frustum_planes(x: f32, y: f32, z: f32, angle: f32, fov_slope: f32, aspect_ratio: f32) -> [FrustumPlane; 2] {
-    let [near_left, near_right, far_left, far_right] = gen_trapezoid_coords(x, z, angle, fov_slope);
-    [
+/// Camera orientation as a matrix whose columns are the right, up and forward axes.
+///
+/// With all angles at zero the camera looks along +Z with +X to the right and +Y up. Yaw turns
+/// it the same way as [`gen_trapezoid_coords`], positive pitch looks up and positive roll raises
+/// the right side of the view.
+pub fn camera_basis(yaw: f32, pitch: f32, roll: f32) -> Mat3A {
+    Mat3A::from_quat(Quat::from_rotation_y(yaw) * Quat::from_rotation_x(-pitch) * Quat::from_rotation_z(roll))
+}
+
+/// Pitch of the classic isometric view, looking down along a diagonal of the voxel grid
+pub fn isometric_pitch() -> f32 {
+    -(1.0 / 2.0f32.sqrt()).atan()
+}
+
+pub fn gen_frustum_planes_from_basis(
+    pos: Vec3A,
+    basis: Mat3A,
+    fov_slope: f32,
+    aspect_ratio: f32,
+    near: f32,
+    far: f32
+) -> [FrustumPlane; 2] {
+    let plane = |distance: f32| {
+        let center = pos + basis.z_axis * distance;
+        let half_width = basis.x_axis * (fov_slope * distance);
+        let half_height = basis.y_axis * (fov_slope * distance / aspect_ratio);
         FrustumPlane {
-            top_left: vec3a(near_left.0, y + fov_slope * NEAR / aspect_ratio, near_left.1),
-            top_right: vec3a(near_right.0, y + fov_slope * NEAR / aspect_ratio, near_right.1),
-            bottom_left: vec3a(near_left.0, y - fov_slope * NEAR / aspect_ratio, near_left.1),
-            bottom_right: vec3a(near_right.0, y - fov_slope * NEAR / aspect_ratio, near_right.1)
-        },
+            top_left: center - half_width + half_height,
+            top_right: center + half_width + half_height,
+            bottom_left: center - half_width - half_height,
+            bottom_right: center + half_width - half_height
+        }
+    };
+    [plane(near), plane(far)]
+}
+
+/// Orthographic counterpart of [`gen_frustum_planes_from_basis`]: both planes span the same
+/// rectangle, `half_width` across, so the rays running between them are parallel
+pub fn gen_ortho_planes_from_basis(
+    pos: Vec3A,
+    basis: Mat3A,
+    half_width: f32,
+    aspect_ratio: f32,
+    near: f32,
+    far: f32
+) -> [FrustumPlane; 2] {
+    let plane = |distance: f32| {
+        let center = pos + basis.z_axis * distance;
+        let half_height = basis.y_axis * (half_width / aspect_ratio);
+        let half_width = basis.x_axis * half_width;
         FrustumPlane {
-            top_left: vec3a(far_left.0, y + fov_slope * FAR / aspect_ratio, far_left.1),
-            top_right: vec3a(far_right.0, y + fov_slope * FAR / aspect_ratio, far_right.1),
-            bottom_left: vec3a(far_left.0, y - fov_slope * FAR / aspect_ratio, far_left.1),
-            bottom_right: vec3a(far_right.0, y - fov_slope * FAR / aspect_ratio, far_right.1)
+            top_left: center - half_width + half_height,
+            top_right: center + half_width + half_height,
+            bottom_left: center - half_width - half_height,
+            bottom_right: center + half_width - half_height
+        }
+    };
+    [plane(near), plane(far)]
+}
+
+/// Yaw only shortcut for [`gen_frustum_planes_from_basis`]
+pub fn gen_frustum_planes(x: f32, y: f32, z: f32, angle: f32, fov_slope: f32, aspect_ratio: f32) -> [FrustumPlane; 2] {
+    gen_frustum_planes_from_basis(vec3a(x, y, z), camera_basis(angle, 0.0, 0.0), fov_slope, aspect_ratio, NEAR, FAR)
+}
+
+#[cfg(test)]
+mod test {
+    use glam::{vec3a, Vec3A};
+
+    use super::{camera_basis, gen_frustum_planes, gen_ortho_planes_from_basis, gen_trapezoid_coords, isometric_pitch, FAR, NEAR};
+
+    fn assert_close(a: Vec3A, b: Vec3A) {
+        assert!((a - b).length() < 1e-3 * b.length().max(1.0), "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_yaw_matches_trapezoid() {
+        let (fov_slope, aspect_ratio) = (1.125, 160.0 / 96.0);
+        for step in 0..8 {
+            let angle = (step as f32 * 45.0 + 10.0).to_radians();
+            let [near_left, near_right, far_left, far_right] = gen_trapezoid_coords(3.0, 7.0, angle, fov_slope);
+            let [near, far] = gen_frustum_planes(3.0, -5.0, 7.0, angle, fov_slope, aspect_ratio);
+
+            let (near_h, far_h) = (fov_slope * NEAR / aspect_ratio, fov_slope * FAR / aspect_ratio);
+            assert_close(near.top_left, vec3a(near_left.0, -5.0 + near_h, near_left.1));
+            assert_close(near.bottom_right, vec3a(near_right.0, -5.0 - near_h, near_right.1));
+            assert_close(far.top_right, vec3a(far_right.0, -5.0 + far_h, far_right.1));
+            assert_close(far.bottom_left, vec3a(far_left.0, -5.0 - far_h, far_left.1));
+        }
+    }
+
+    #[test]
+    fn test_pitch_and_roll() {
+        let up = camera_basis(0.3, 45.0f32.to_radians(), 0.0);
+        assert!(up.z_axis.y > 0.7 && up.y_axis.y > 0.7);
+        assert!(up.x_axis.y.abs() < 1e-6);
+
+        let down = camera_basis(0.3, -45.0f32.to_radians(), 0.0);
+        assert!(down.z_axis.y < -0.7);
+
+        let rolled = camera_basis(0.0, 0.0, 30.0f32.to_radians());
+        assert_close(rolled.z_axis, Vec3A::Z);
+        assert!(rolled.x_axis.y > 0.49);
+    }
+
+    #[test]
+    fn test_ortho_planes() {
+        let basis = camera_basis(45.0f32.to_radians(), isometric_pitch(), 0.0);
+        assert_close(basis.z_axis, vec3a(1.0, -1.0, 1.0).normalize());
+
+        let [near, far] = gen_ortho_planes_from_basis(vec3a(1.0, 2.0, 3.0), basis, 80.0, 160.0 / 96.0, NEAR, FAR);
+        for (a, b) in [
+            (near.top_left, far.top_left),
+            (near.top_right, far.top_right),
+            (near.bottom_left, far.bottom_left),
+            (near.bottom_right, far.bottom_right)
+        ] {
+            assert_close((b - a).normalize(), basis.z_axis);
         }
-    ]
+        assert!(((near.top_right - near.top_left).length() - 160.0).abs() < 1e-3);
+        assert!(((near.top_left - near.bottom_left).length() - 96.0).abs() < 1e-3);
+    }
 }
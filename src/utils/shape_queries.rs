@@ -0,0 +1,308 @@
+use glam::{vec3a, Vec3A};
+
+use crate::voxel_model::{VoxelData, VoxelDataVisitor, VoxelModel};
+
+/// A solid shape which can be tested against voxel cells.
+///
+/// Overlaps are strict: a shape merely touching a face of a cell does not overlap it,
+/// so a box resting on the ground is not considered to be inside it.
+pub trait OverlapShape {
+    fn overlaps_box(&self, p0: Vec3A, p1: Vec3A) -> bool;
+    fn bounds(&self) -> (Vec3A, Vec3A);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vec3A,
+    pub max: Vec3A
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sphere {
+    pub center: Vec3A,
+    pub radius: f32
+}
+
+/// Sphere swept along the segment from `a` to `b`
+#[derive(Clone, Copy, Debug)]
+pub struct Capsule {
+    pub a: Vec3A,
+    pub b: Vec3A,
+    pub radius: f32
+}
+
+#[inline(always)]
+fn point_box_distance_squared(p: Vec3A, p0: Vec3A, p1: Vec3A) -> f32 {
+    let d = p.clamp(p0, p1) - p;
+    d.dot(d)
+}
+
+/// Exact squared distance between the segment `a..b` and the box `[p0, p1]`.
+///
+/// Along the segment the distance is a piecewise quadratic which changes shape only where the
+/// segment crosses one of the box planes, so it is minimized analytically on each piece.
+fn segment_box_distance_squared(a: Vec3A, b: Vec3A, p0: Vec3A, p1: Vec3A) -> f32 {
+    let d = b - a;
+
+    let mut ts = [0.0; 8];
+    ts[1] = 1.0;
+    let mut ts_len = 2;
+    for axis in 0..3 {
+        if d[axis] == 0.0 { continue; }
+        for bound in [p0[axis], p1[axis]] {
+            let t = (bound - a[axis]) / d[axis];
+            if t > 0.0 && t < 1.0 {
+                ts[ts_len] = t;
+                ts_len += 1;
+            }
+        }
+    }
+    let ts = &mut ts[..ts_len];
+    ts.sort_by(|x, y| x.total_cmp(y));
+
+    let distance_at = |t: f32| point_box_distance_squared(a + d * t, p0, p1);
+    let mut best = ts.iter().map(|&t| distance_at(t)).fold(f32::INFINITY, f32::min);
+
+    for piece in ts.windows(2) {
+        let (t0, t1) = (piece[0], piece[1]);
+        let p_mid = a + d * ((t0 + t1) * 0.5);
+
+        // sum of (a + d * t - bound)^2 over the axes where the piece lies outside of the box
+        let (mut quad, mut lin) = (0.0, 0.0);
+        for axis in 0..3 {
+            let bound = if p_mid[axis] < p0[axis] {
+                p0[axis]
+            } else if p_mid[axis] > p1[axis] {
+                p1[axis]
+            } else {
+                continue;
+            };
+            quad += d[axis] * d[axis];
+            lin += 2.0 * d[axis] * (a[axis] - bound);
+        }
+        if quad > 0.0 {
+            best = best.min(distance_at((-lin / (2.0 * quad)).clamp(t0, t1)));
+        }
+    }
+    best
+}
+
+impl OverlapShape for Aabb {
+    fn overlaps_box(&self, p0: Vec3A, p1: Vec3A) -> bool {
+        self.min.cmplt(p1).all() && self.max.cmpgt(p0).all()
+    }
+
+    fn bounds(&self) -> (Vec3A, Vec3A) {
+        (self.min, self.max)
+    }
+}
+
+impl OverlapShape for Sphere {
+    fn overlaps_box(&self, p0: Vec3A, p1: Vec3A) -> bool {
+        point_box_distance_squared(self.center, p0, p1) < self.radius * self.radius
+    }
+
+    fn bounds(&self) -> (Vec3A, Vec3A) {
+        (self.center - Vec3A::splat(self.radius), self.center + Vec3A::splat(self.radius))
+    }
+}
+
+impl OverlapShape for Capsule {
+    fn overlaps_box(&self, p0: Vec3A, p1: Vec3A) -> bool {
+        segment_box_distance_squared(self.a, self.b, p0, p1) < self.radius * self.radius
+    }
+
+    fn bounds(&self) -> (Vec3A, Vec3A) {
+        (
+            self.a.min(self.b) - Vec3A::splat(self.radius),
+            self.a.max(self.b) + Vec3A::splat(self.radius)
+        )
+    }
+}
+
+/// Checks a shape against the solid voxels of a model placed at `pos`.
+///
+/// Subtrees which the shape does not reach are pruned. Once a solid voxel is found the rest of
+/// the traversal is skipped, unless `touched` is given, in which case every solid unit cell
+/// overlapping the shape is collected there in model space coordinates.
+pub struct ShapeOverlapVisitor<'a, S: OverlapShape> {
+    pub shape: &'a S,
+    pub pos: Vec3A,
+    pub hit: bool,
+    pub touched: Option<&'a mut Vec<[usize; 3]>>
+}
+
+impl<'a, S: OverlapShape> VoxelDataVisitor for ShapeOverlapVisitor<'a, S> {
+    fn visit(
+        &mut self,
+        min_c: &[usize],
+        max_c: &[usize],
+        data: &VoxelData
+    ) -> bool {
+        if self.hit && self.touched.is_none() { return false; }
+
+        let p0 = self.pos + vec3a(min_c[0] as f32, min_c[1] as f32, min_c[2] as f32);
+        let p1 = self.pos + vec3a(max_c[0] as f32, max_c[1] as f32, max_c[2] as f32);
+        if !self.shape.overlaps_box(p0, p1) { return false; }
+
+        match data {
+            VoxelData::Node2x2x2 { .. } => true,
+            VoxelData::Leaf { color_id } if *color_id == 0 => false,
+            VoxelData::Leaf { .. } => {
+                self.hit = true;
+                let Some(touched) = self.touched.as_mut() else { return false; };
+
+                // compacted leafs span many cells, so only those within the shape bounds are checked
+                let (shape_min, shape_max) = self.shape.bounds();
+                let (shape_min, shape_max) = (shape_min - self.pos, shape_max - self.pos);
+                let cell_range = |axis: usize| {
+                    let from = (shape_min[axis].floor().max(min_c[axis] as f32) as usize).min(max_c[axis]);
+                    let to = (shape_max[axis].ceil().max(0.0) as usize).clamp(from, max_c[axis]);
+                    from..to
+                };
+
+                for z in cell_range(2) {
+                    for y in cell_range(1) {
+                        for x in cell_range(0) {
+                            let c0 = self.pos + vec3a(x as f32, y as f32, z as f32);
+                            if self.shape.overlaps_box(c0, c0 + Vec3A::ONE) {
+                                touched.push([x, y, z]);
+                            }
+                        }
+                    }
+                }
+                false
+            }
+        }
+    }
+}
+
+pub fn overlaps_shape<S: OverlapShape>(
+    model: &VoxelModel,
+    pos: Vec3A,
+    shape: &S,
+    touched: Option<&mut Vec<[usize; 3]>>
+) -> bool {
+    let mut visitor = ShapeOverlapVisitor { shape, pos, hit: false, touched };
+    model.traverse(&mut visitor);
+    visitor.hit
+}
+
+pub fn overlaps_aabb(
+    model: &VoxelModel,
+    pos: Vec3A,
+    min: Vec3A,
+    max: Vec3A,
+    touched: Option<&mut Vec<[usize; 3]>>
+) -> bool {
+    overlaps_shape(model, pos, &Aabb { min, max }, touched)
+}
+
+pub fn overlaps_sphere(
+    model: &VoxelModel,
+    pos: Vec3A,
+    center: Vec3A,
+    radius: f32,
+    touched: Option<&mut Vec<[usize; 3]>>
+) -> bool {
+    overlaps_shape(model, pos, &Sphere { center, radius }, touched)
+}
+
+pub fn overlaps_capsule(
+    model: &VoxelModel,
+    pos: Vec3A,
+    a: Vec3A,
+    b: Vec3A,
+    radius: f32,
+    touched: Option<&mut Vec<[usize; 3]>>
+) -> bool {
+    overlaps_shape(model, pos, &Capsule { a, b, radius }, touched)
+}
+
+#[cfg(test)]
+mod test {
+    use glam::{vec3a, Vec3A};
+
+    use crate::voxel_model::VoxelModel;
+
+    use super::{overlaps_aabb, overlaps_capsule, overlaps_sphere, segment_box_distance_squared, OverlapShape, Capsule};
+
+    fn is_solid_sphere_cell([x, y, z]: [usize; 3]) -> bool {
+        let diff = vec3a(x as f32, y as f32, z as f32) - Vec3A::splat(15.5);
+        diff.dot(diff) <= 15.5 * 15.5
+    }
+
+    #[test]
+    fn test_overlaps_aabb() {
+        let sphere = VoxelModel::make_sphere32x32x32(0, 5);
+        let pos = vec3a(100.0, 0.0, -50.0);
+
+        assert!(overlaps_aabb(&sphere, pos, pos + Vec3A::splat(14.0), pos + Vec3A::splat(18.0), None));
+        // inside the bounds of the model, but in the empty corner next to the sphere
+        assert!(!overlaps_aabb(&sphere, pos, pos, pos + Vec3A::splat(2.0), None));
+        // resting exactly on the top of the model bounds
+        assert!(!overlaps_aabb(&sphere, pos, pos + vec3a(12.0, 32.0, 12.0), pos + vec3a(20.0, 40.0, 20.0), None));
+
+        let mut touched = Vec::new();
+        assert!(overlaps_aabb(&sphere, pos, pos + vec3a(14.5, -4.0, 14.5), pos + vec3a(16.5, 1.5, 16.5), Some(&mut touched)));
+        touched.sort();
+        let mut expected = Vec::new();
+        for x in 14..17 {
+            for y in 0..2 {
+                for z in 14..17 {
+                    if is_solid_sphere_cell([x, y, z]) { expected.push([x, y, z]); }
+                }
+            }
+        }
+        assert_eq!(touched, expected);
+    }
+
+    #[test]
+    fn test_overlaps_sphere_and_capsule() {
+        let sphere = VoxelModel::make_sphere32x32x32(0, 5);
+        let pos = Vec3A::ZERO;
+
+        assert!(overlaps_sphere(&sphere, pos, vec3a(16.0, 16.0, 16.0), 1.0, None));
+        assert!(!overlaps_sphere(&sphere, pos, vec3a(40.0, 16.0, 16.0), 4.0, None));
+        assert!(!overlaps_sphere(&sphere, pos, vec3a(1.0, 1.0, 1.0), 2.0, None));
+
+        assert!(!overlaps_capsule(&sphere, pos, vec3a(40.0, -8.0, 16.0), vec3a(40.0, 40.0, 16.0), 4.0, None));
+        assert!(overlaps_capsule(&sphere, pos, vec3a(40.0, -8.0, 16.0), vec3a(-8.0, 40.0, 16.0), 1.0, None));
+
+        let (center, radius) = (vec3a(16.0, 30.0, 16.0), 3.5);
+        let mut touched = Vec::new();
+        assert!(overlaps_sphere(&sphere, pos, center, radius, Some(&mut touched)));
+        touched.sort();
+        let mut expected = Vec::new();
+        for x in 0..32 {
+            for y in 0..32 {
+                for z in 0..32 {
+                    let c0 = vec3a(x as f32, y as f32, z as f32);
+                    let d = c0.max(center.min(c0 + Vec3A::ONE)) - center;
+                    if is_solid_sphere_cell([x, y, z]) && d.dot(d) < radius * radius {
+                        expected.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        assert_eq!(touched, expected);
+    }
+
+    #[test]
+    fn test_segment_box_distance() {
+        let (p0, p1) = (Vec3A::ZERO, Vec3A::splat(2.0));
+
+        // crossing the box
+        assert_eq!(segment_box_distance_squared(vec3a(-1.0, 1.0, 1.0), vec3a(3.0, 1.0, 1.0), p0, p1), 0.0);
+        // parallel to a face
+        assert_eq!(segment_box_distance_squared(vec3a(-1.0, 3.0, 1.0), vec3a(3.0, 3.0, 1.0), p0, p1), 1.0);
+        // passing diagonally by an edge: closest at (2.5, 2.5), 0.5 away along x and y
+        let d = segment_box_distance_squared(vec3a(5.0, 0.0, 1.0), vec3a(0.0, 5.0, 1.0), p0, p1);
+        assert!((d - 0.5).abs() < 1e-5, "{}", d);
+
+        let capsule = Capsule { a: vec3a(5.0, 0.0, 1.0), b: vec3a(0.0, 5.0, 1.0), radius: 0.75 };
+        assert!(capsule.overlaps_box(p0, p1));
+        let capsule = Capsule { radius: 0.7, ..capsule };
+        assert!(!capsule.overlaps_box(p0, p1));
+    }
+}
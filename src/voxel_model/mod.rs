@@ -109,16 +109,19 @@ impl VoxelModel {
     pub fn traverse<T: VoxelDataVisitor>(&self, visitor: &mut T) {
         self.data.traverse([0; 3], self.size, visitor)
     }
+    /// 32x32x32 model with the voxel at `(x, y, z)` colored `color_at(x, y, z)`
+    pub fn make_32x32x32(color_at: impl Fn(usize, usize, usize) -> u8) -> Self {
+        let data = VoxelData::make_32x32x32(|x, y, z| VoxelData::make_leaf(color_at(x, y, z))).compact();
+        Self { size: [32; 3], data }
+    }
     pub fn make_sphere32x32x32(transparent_color: u8, opaque_color: u8) -> Self {
         let center_p = vec3a(15.5, 15.5, 15.5);
         let mag_sqr = 15.5 * 15.5;
-        let data = VoxelData::make_32x32x32(|x, y, z| {
+        Self::make_32x32x32(|x, y, z| {
            let p = vec3a(x as _, y as _, z as _);
            let diff = p - center_p;
            let dot = diff.dot(diff);
-           let color = if dot <= mag_sqr { opaque_color } else { transparent_color };
-           VoxelData::make_leaf(color)
-        }).compact();
-        Self { size: [32; 3], data }
+           if dot <= mag_sqr { opaque_color } else { transparent_color }
+        })
     }
 }